@@ -0,0 +1,131 @@
+use std::collections::BTreeSet;
+
+use bevy::{prelude::*, sprite::collide_aabb::Collision};
+
+use super::{Contact, Sensor};
+
+/// Sent every tick for every overlapping pair.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CollisionEvent {
+    pub entity: Entity,
+    pub other: Entity,
+    /// side of `other` that `entity` hit
+    pub side: Collision,
+    /// penetration depth, always positive
+    pub depth: f32,
+    /// direction pushing `entity` out of `other`
+    pub normal: Vec2,
+}
+
+impl CollisionEvent {
+    pub fn new(entity: Entity, other: Entity, contact: Contact) -> Self {
+        Self {
+            entity,
+            other,
+            side: contact.side(),
+            depth: contact.depth,
+            normal: contact.normal,
+        }
+    }
+
+    fn pair(&self) -> (Entity, Entity) {
+        (self.entity, self.other)
+    }
+}
+
+/// First tick a pair overlaps.
+#[derive(Event, Debug, Clone, Copy, Deref)]
+pub struct CollisionStarted(pub CollisionEvent);
+
+/// Every following tick the pair keeps overlapping.
+#[derive(Event, Debug, Clone, Copy, Deref)]
+pub struct CollisionOngoing(pub CollisionEvent);
+
+/// First tick the pair stopped overlapping.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CollisionEnded {
+    pub entity: Entity,
+    pub other: Entity,
+}
+
+/// `entity` started overlapping the `sensor`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SensorEntered {
+    pub sensor: Entity,
+    pub entity: Entity,
+}
+
+/// `entity` stopped overlapping the `sensor`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SensorExited {
+    pub sensor: Entity,
+    pub entity: Entity,
+}
+
+/// Logs sensor overlaps, to check where sensors are while building levels.
+pub fn log_sensor_events(
+    mut sensor_entered_events: EventReader<SensorEntered>,
+    mut sensor_exited_events: EventReader<SensorExited>,
+) {
+    for event in sensor_entered_events.read() {
+        debug!("{:?} entered sensor {:?}", event.entity, event.sensor);
+    }
+    for event in sensor_exited_events.read() {
+        debug!("{:?} exited sensor {:?}", event.entity, event.sensor);
+    }
+}
+
+/// Pairs that were overlapping in the last tick.
+#[derive(Resource, Default)]
+pub struct Contacts(BTreeSet<(Entity, Entity)>);
+
+impl Contacts {
+    pub fn contains(&self, entity: Entity, other: Entity) -> bool {
+        self.0.contains(&(entity, other))
+    }
+}
+
+pub fn track_contacts(
+    mut contacts: ResMut<Contacts>,
+    mut collision_events: EventReader<CollisionEvent>,
+    mut started_events: EventWriter<CollisionStarted>,
+    mut ongoing_events: EventWriter<CollisionOngoing>,
+    mut ended_events: EventWriter<CollisionEnded>,
+    mut sensor_entered_events: EventWriter<SensorEntered>,
+    mut sensor_exited_events: EventWriter<SensorExited>,
+    sensor_query: Query<(), With<Sensor>>,
+) {
+    let mut current = BTreeSet::new();
+    for event in collision_events.read() {
+        // a pair can only be reported once per tick
+        if !current.insert(event.pair()) {
+            continue;
+        }
+        if contacts.contains(event.entity, event.other) {
+            ongoing_events.send(CollisionOngoing(*event));
+        } else {
+            started_events.send(CollisionStarted(*event));
+            if sensor_query.contains(event.other) {
+                sensor_entered_events.send(SensorEntered {
+                    sensor: event.other,
+                    entity: event.entity,
+                });
+            }
+        }
+    }
+
+    for (entity, other) in contacts.0.difference(&current) {
+        ended_events.send(CollisionEnded {
+            entity: *entity,
+            other: *other,
+        });
+        if sensor_query.contains(*other) {
+            sensor_exited_events.send(SensorExited {
+                sensor: *other,
+                entity: *entity,
+            });
+        }
+    }
+
+    contacts.0 = current;
+}
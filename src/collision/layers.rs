@@ -0,0 +1,75 @@
+use std::ops::{BitAnd, BitOr, Not};
+
+use bevy::prelude::*;
+
+/// Bit set of collision groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
+pub struct CollisionLayers(u32);
+
+impl CollisionLayers {
+    pub const PLAYER: Self = Self(1 << 0);
+    pub const WAGON: Self = Self(1 << 1);
+    pub const OBSTACLE: Self = Self(1 << 2);
+    /// Pickups need a `Sensor` too, otherwise they block the player who collides
+    /// with this layer to collect them.
+    pub const PICKUP: Self = Self(1 << 3);
+    pub const ENEMY: Self = Self(1 << 4);
+    pub const SENSOR: Self = Self(1 << 5);
+    pub const ALL: Self = Self(u32::MAX);
+
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for CollisionLayers {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for CollisionLayers {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Not for CollisionLayers {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
+
+/// Which groups a collider belongs to and which groups it interacts with.
+/// Colliders without this component belong to and interact with every group.
+#[derive(Component, Debug, Clone, Copy, Reflect)]
+pub struct CollisionGroups {
+    pub memberships: CollisionLayers,
+    pub filters: CollisionLayers,
+}
+
+impl Default for CollisionGroups {
+    fn default() -> Self {
+        Self::new(CollisionLayers::ALL, CollisionLayers::ALL)
+    }
+}
+
+impl CollisionGroups {
+    pub fn new(memberships: CollisionLayers, filters: CollisionLayers) -> Self {
+        Self {
+            memberships,
+            filters,
+        }
+    }
+
+    /// Both colliders need to accept each other to interact.
+    pub fn interacts_with(&self, other: &Self) -> bool {
+        self.memberships.intersects(other.filters) && other.memberships.intersects(self.filters)
+    }
+}
@@ -1,101 +1,494 @@
-use bevy::{prelude::*, sprite::collide_aabb::Collision};
+use bevy::{
+    ecs::query::ReadOnlyWorldQuery, math::Rect, prelude::*, sprite::collide_aabb::Collision,
+};
 
-use super::{Player, Velocity};
+use serde::Deserialize;
 
-#[derive(Event, Default)]
-pub struct CollisionEvent;
+use super::Velocity;
 
-#[derive(Component)]
+mod events;
+mod layers;
+mod shapes;
+mod spatial_hash;
+pub use events::*;
+pub use layers::*;
+pub use shapes::*;
+pub use spatial_hash::*;
+
+#[derive(Component, Clone, Deserialize)]
 pub enum Collider {
     Quad(Vec2),
+    Circle(f32),
+    /// Vertical capsule, `half_height` is the distance from the center to each cap's center.
+    Capsule {
+        half_height: f32,
+        radius: f32,
+    },
+    /// Right triangle filling `size`, rising towards +x when `rises_right`.
+    Slope {
+        size: Vec2,
+        rises_right: bool,
+    },
+    /// Convex polygon, points in counter-clockwise order around the entity's origin.
+    Polygon(Vec<Vec2>),
+}
+
+impl Collider {
+    /// Narrowphase shape given the collider's world transform (rotation is ignored).
+    pub fn shape(&self, transform: &Transform) -> Shape {
+        let scale = transform.scale.truncate();
+        let shape = match self {
+            Collider::Quad(size) => {
+                let half = *size / 2.;
+                Shape {
+                    points: vec![
+                        Vec2::new(-half.x, -half.y),
+                        Vec2::new(half.x, -half.y),
+                        Vec2::new(half.x, half.y),
+                        Vec2::new(-half.x, half.y),
+                    ],
+                    radius: 0.,
+                }
+            }
+            Collider::Circle(radius) => Shape {
+                points: vec![Vec2::ZERO],
+                radius: *radius,
+            },
+            Collider::Capsule {
+                half_height,
+                radius,
+            } => Shape {
+                points: vec![Vec2::new(0., -half_height), Vec2::new(0., *half_height)],
+                radius: *radius,
+            },
+            Collider::Slope { size, rises_right } => {
+                let half = *size / 2.;
+                let top = if *rises_right { half.x } else { -half.x };
+                Shape {
+                    points: vec![
+                        Vec2::new(-half.x, -half.y),
+                        Vec2::new(half.x, -half.y),
+                        Vec2::new(top, half.y),
+                    ],
+                    radius: 0.,
+                }
+            }
+            Collider::Polygon(points) => Shape {
+                points: points.clone(),
+                radius: 0.,
+            },
+        };
+
+        Shape {
+            points: shape.points.iter().map(|point| *point * scale).collect(),
+            radius: shape.radius * scale.abs().max_element(),
+        }
+        .translate(transform.translation.truncate())
+    }
+
+    /// World space bounding box given the collider's world transform.
+    pub fn aabb(&self, transform: &Transform) -> Rect {
+        self.shape(transform).aabb()
+    }
+}
+
+/// Body moved by its `Velocity`, affected by gravity and resolved against static
+/// (no `Velocity`) and kinematic (`Velocity` without `Dynamic`, e.g. the train) colliders.
+#[derive(Component, Default)]
+pub struct Dynamic;
+
+#[derive(Bundle, Default)]
+pub struct DynamicBundle {
+    pub dynamic: Dynamic,
+    pub velocity: Velocity,
+    pub grounded_on: GroundedOn,
+    pub drop_through: DropThrough,
+}
+
+/// Collider that reports overlaps (see `SensorEntered` and `SensorExited`) but never
+/// blocks movement, e.g. checkpoints, pickups or kill zones.
+#[derive(Component, Default)]
+pub struct Sensor;
+
+/// Collider that can be passed through from below and landed on from above.
+#[derive(Component, Default)]
+pub struct OneWay;
+
+/// One-way collider the body is dropping through, ignored until they stop overlapping.
+#[derive(Component, Default, Deref, DerefMut)]
+pub struct DropThrough(Option<Entity>);
+
+/// Collider entity the body is standing on, if any.
+#[derive(Component, Default, Deref, DerefMut)]
+pub struct GroundedOn(Option<Entity>);
+
+/// Velocity of a platform: its own `Velocity` or the one of its closest ancestor
+/// (e.g. a wagon collider moves with the train entity).
+pub fn platform_velocity<F: ReadOnlyWorldQuery>(
+    entity: Entity,
+    velocities: &Query<&Velocity, F>,
+    parents: &Query<&Parent>,
+) -> Vec2 {
+    std::iter::once(entity)
+        .chain(parents.iter_ancestors(entity))
+        .find_map(|entity| velocities.get(entity).ok())
+        .map_or(Vec2::ZERO, |velocity| **velocity)
+}
+
+/// World transform of `entity` composed from the local transforms of its ancestors.
+/// `GlobalTransform` is only propagated in `PostUpdate`, so it lags behind while
+/// the fixed timestep keeps moving parents (e.g. wagons inside the moving train).
+pub fn world_transform<F: ReadOnlyWorldQuery>(
+    entity: Entity,
+    transforms: &Query<&Transform, F>,
+    parents: &Query<&Parent>,
+) -> GlobalTransform {
+    let local = transforms.get(entity).copied().unwrap_or_default();
+    match parents.get(entity) {
+        Ok(parent) => world_transform(parent.get(), transforms, parents).mul_transform(local),
+        Err(_) => GlobalTransform::from(local),
+    }
 }
 
 pub fn check_for_collisions(
-    mut player_query: Query<(&mut Velocity, &mut Transform, &Collider), With<Player>>,
-    collider_query: Query<(&Transform, &Collider), (With<Collider>, Without<Player>)>,
+    mut body_query: Query<
+        (
+            Entity,
+            &mut Velocity,
+            &mut Transform,
+            &mut GroundedOn,
+            &mut DropThrough,
+            &Collider,
+            Option<&CollisionGroups>,
+        ),
+        With<Dynamic>,
+    >,
+    collider_query: Query<(&Collider, Option<&CollisionGroups>, Option<&Sensor>), Without<Dynamic>>,
+    transform_query: Query<&Transform, Without<Dynamic>>,
+    one_way_query: Query<(), With<OneWay>>,
+    spatial_hash: Res<SpatialHash>,
+    velocity_query: Query<&Velocity, Without<Dynamic>>,
+    parent_query: Query<&Parent>,
     mut collision_events: EventWriter<CollisionEvent>,
+    time: Res<Time>,
 ) {
-    let (mut velocity, mut transform, collider) = player_query.single_mut();
-    // offset/scaled value, not actual size
-    let offset = 4.;
-    let player_size = match collider {
-        Collider::Quad(size) => *size + Vec2::new(offset, offset),
-    };
-    let player_translation = transform.translation + Vec3::new(offset / 2., offset / 2., 0.);
-    for (other_transform, other_collider) in collider_query.iter() {
-        let other_size = match other_collider {
-            Collider::Quad(size) => *size,
+    let delta = time.delta_seconds();
+    // resolve bodies in a stable order so the simulation is deterministic
+    let mut bodies: Vec<Entity> = body_query.iter().map(|(entity, ..)| entity).collect();
+    bodies.sort_unstable();
+    for body in bodies {
+        let Ok((
+            entity,
+            mut velocity,
+            mut transform,
+            mut grounded_on,
+            mut drop_through,
+            collider,
+            groups,
+        )) = body_query.get_mut(body)
+        else {
+            continue;
         };
-        // is colliding
-        let collision = collide_v2(
-            player_translation,
-            player_size,
-            other_transform.translation,
-            other_size,
-        );
+        let groups = groups.copied().unwrap_or_default();
+        // offset/scaled value, not actual size
+        let offset = 4.;
+        let body_shape = collider
+            .shape(&transform)
+            .inflate(offset / 2.)
+            .translate(Vec2::new(offset / 2., offset / 2.));
+        let mut ground = None;
+        let mut still_dropping = false;
+        // only test the colliders around the body, including where it's moving to
+        let search_area = body_shape.aabb().inset(velocity.length() * delta);
+        for other_entity in spatial_hash.colliders_in_aabb(search_area) {
+            let Ok((other_collider, other_groups, sensor)) = collider_query.get(other_entity)
+            else {
+                continue;
+            };
+            if !groups.interacts_with(&other_groups.copied().unwrap_or_default()) {
+                continue;
+            }
+            let other_transform =
+                world_transform(other_entity, &transform_query, &parent_query).compute_transform();
+            // is colliding
+            let Some(contact) = collide(&body_shape, &other_collider.shape(&other_transform))
+            else {
+                continue;
+            };
+
+            collision_events.send(CollisionEvent::new(entity, other_entity, contact));
+
+            if sensor.is_some() {
+                continue;
+            }
+
+            if one_way_query.contains(other_entity) {
+                if **drop_through == Some(other_entity) {
+                    still_dropping = true;
+                    continue;
+                }
+                // only land when falling onto it, not when crossing it from below or the sides
+                let crossed_this_tick = contact.depth <= -velocity.y * delta + 1.;
+                if contact.side() != Collision::Top || velocity.y > 0. || !crossed_this_tick {
+                    continue;
+                }
+            }
 
-        if let Some((collision, diff)) = collision {
-            match collision {
+            match contact.side() {
                 Collision::Left if velocity.x > 0. => velocity.x = 0.,
                 Collision::Right if velocity.x < 0. => velocity.x = 0.,
                 Collision::Top if velocity.y < 0. => {
                     velocity.y = 0.;
+                    // lift the body vertically so it walks up slopes instead of sliding
+                    let lift = contact.depth / contact.normal.y;
                     let offset = 0.1;
-                    if diff < -offset - 0.01 {
-                        println!("diff: {}", diff);
-                        transform.translation.y -= diff + offset;
+                    if lift > offset + 0.01 {
+                        transform.translation.y += lift - offset;
                     }
+                    ground = Some(other_entity);
                 }
                 Collision::Bottom if velocity.y > 0. => velocity.y = 0.,
                 _ => {}
             }
+        }
+
+        if !still_dropping {
+            **drop_through = None;
+        }
 
-            collision_events.send(CollisionEvent);
+        if ground != **grounded_on {
+            // keep the world velocity continuous: the platform velocity is added in
+            // `apply_velocity`, so the body velocity is stored relative to it
+            if let Some(old_ground) = **grounded_on {
+                // momentum inheritance when jumping or walking off a platform
+                **velocity += platform_velocity(old_ground, &velocity_query, &parent_query);
+            }
+            if let Some(new_ground) = ground {
+                let landing = platform_velocity(new_ground, &velocity_query, &parent_query);
+                velocity.x -= landing.x;
+            }
+            **grounded_on = ground;
+        }
+
+        // continuous collision: clip the motion against the first collider in the way,
+        // so fast bodies don't tunnel through thin colliders like the unions
+        let platform = grounded_on.map_or(Vec2::ZERO, |ground| {
+            platform_velocity(ground, &velocity_query, &parent_query)
+        });
+        let motion = (**velocity + platform) * delta;
+        let body_aabb = body_shape.aabb();
+        let swept_area = body_aabb.union(Rect::from_center_size(
+            body_aabb.center() + motion,
+            body_aabb.size(),
+        ));
+        let mut first_hit: Option<(SweepHit, Vec2)> = None;
+        for other_entity in spatial_hash.colliders_in_aabb(swept_area) {
+            let Ok((_, other_groups, sensor)) = collider_query.get(other_entity) else {
+                continue;
+            };
+            if sensor.is_some()
+                || !groups.interacts_with(&other_groups.copied().unwrap_or_default())
+            {
+                continue;
+            }
+            let one_way = one_way_query.contains(other_entity);
+            if one_way && **drop_through == Some(other_entity) {
+                continue;
+            }
+            let Some(other_aabb) = spatial_hash.aabb(other_entity) else {
+                continue;
+            };
+            let relative_motion =
+                motion - platform_velocity(other_entity, &velocity_query, &parent_query) * delta;
+            if let Some(hit) = sweep_aabb(body_aabb, relative_motion, other_aabb) {
+                if one_way && hit.normal != Vec2::Y {
+                    continue;
+                }
+                if first_hit.map_or(true, |(first, _)| hit.time < first.time) {
+                    first_hit = Some((hit, relative_motion));
+                }
+            }
+        }
+
+        if let Some((hit, relative_motion)) = first_hit {
+            let into = relative_motion.dot(hit.normal);
+            // stop just inside the surface so the overlap test reports the contact next tick
+            let skin = 0.5;
+            let allowed = into * hit.time - skin;
+            if allowed > into {
+                **velocity += hit.normal * (allowed - into) / delta;
+            }
         }
     }
 }
 
-pub fn collide_v2(
-    a_pos: Vec3,
-    a_size: Vec2,
-    b_pos: Vec3,
-    b_size: Vec2,
-) -> Option<(Collision, f32)> {
-    let a_min = a_pos.truncate() - a_size / 2.0;
-    let a_max = a_pos.truncate() + a_size / 2.0;
-
-    let b_min = b_pos.truncate() - b_size / 2.0;
-    let b_max = b_pos.truncate() + b_size / 2.0;
-
-    // check to see if the two rectangles are intersecting
-    if a_min.x < b_max.x && a_max.x > b_min.x && a_min.y < b_max.y && a_max.y > b_min.y {
-        // check to see if we hit on the left or right side
-        let (x_collision, x_depth) = if a_min.x < b_min.x && a_max.x > b_min.x && a_max.x < b_max.x
-        {
-            (Collision::Left, b_min.x - a_max.x)
-        } else if a_min.x > b_min.x && a_min.x < b_max.x && a_max.x > b_max.x {
-            (Collision::Right, a_min.x - b_max.x)
-        } else {
-            (Collision::Inside, -f32::INFINITY)
-        };
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::SystemState;
 
-        // check to see if we hit on the top or bottom side
-        let (y_collision, y_depth) = if a_min.y < b_min.y && a_max.y > b_min.y && a_max.y < b_max.y
-        {
-            (Collision::Bottom, b_min.y - a_max.y)
-        } else if a_min.y > b_min.y && a_min.y < b_max.y && a_max.y > b_max.y {
-            (Collision::Top, a_min.y - b_max.y)
-        } else {
-            (Collision::Inside, -f32::INFINITY)
-        };
+    use super::*;
+
+    /// Runs the broadphase and the collision pass on every update. The clock doesn't
+    /// advance, so the tests move the bodies themselves.
+    fn app() -> App {
+        let mut app = App::new();
+        app.init_resource::<SpatialHash>()
+            .init_resource::<Time>()
+            .add_event::<CollisionEvent>()
+            .add_systems(
+                Update,
+                (
+                    remove_despawned_colliders,
+                    update_spatial_hash,
+                    check_for_collisions,
+                )
+                    .chain(),
+            );
+        app
+    }
+
+    fn falling_body(app: &mut App, collider: Collider, position: Vec2) -> Entity {
+        let mut velocity = Velocity::default();
+        velocity.y = -1.;
+        app.world
+            .spawn((
+                TransformBundle::from_transform(Transform::from_translation(position.extend(0.))),
+                collider,
+                DynamicBundle {
+                    velocity,
+                    ..default()
+                },
+            ))
+            .id()
+    }
+
+    /// Train at the origin, its wagon, and the wagon's child collider. The body waits
+    /// where the collider ends up once the train moves 200 units forward.
+    fn setup() -> (App, Entity, Entity, Entity) {
+        let mut app = app();
+
+        let collider = app
+            .world
+            .spawn((
+                TransformBundle::from_transform(Transform::from_xyz(0., 10., 0.)),
+                Collider::Quad(Vec2::new(64., 16.)),
+            ))
+            .id();
+        let wagon = app
+            .world
+            .spawn(TransformBundle::from_transform(Transform::from_xyz(
+                100., 0., 0.,
+            )))
+            .add_child(collider)
+            .id();
+        let train = app
+            .world
+            .spawn(TransformBundle::default())
+            .add_child(wagon)
+            .id();
+
+        let body = falling_body(
+            &mut app,
+            Collider::Quad(Vec2::new(16., 16.)),
+            Vec2::new(300., 25.),
+        );
+
+        app.update();
+        (app, train, collider, body)
+    }
 
-        // if we had an "x" and a "y" collision, pick the "primary" side using penetration depth
-        if y_depth.abs() < x_depth.abs() {
-            Some((y_collision, y_depth))
-        } else {
-            Some((x_collision, x_depth))
+    fn collision_events(app: &App) -> Vec<CollisionEvent> {
+        let events = app.world.resource::<Events<CollisionEvent>>();
+        events.get_reader().read(events).copied().collect()
+    }
+
+    #[test]
+    fn world_transform_composes_ancestors() {
+        let (mut app, train, collider, _) = setup();
+        app.world.get_mut::<Transform>(train).unwrap().translation.x = 200.;
+
+        let mut state = SystemState::<(Query<&Transform>, Query<&Parent>)>::new(&mut app.world);
+        let (transform_query, parent_query) = state.get(&app.world);
+        let transform = world_transform(collider, &transform_query, &parent_query);
+        assert_eq!(transform.translation(), Vec3::new(300., 10., 0.));
+    }
+
+    #[test]
+    fn spatial_hash_follows_the_parent() {
+        let (mut app, train, collider, _) = setup();
+        let aabb = app.world.resource::<SpatialHash>().aabb(collider).unwrap();
+        assert_eq!(aabb.center(), Vec2::new(100., 10.));
+
+        app.world.get_mut::<Transform>(train).unwrap().translation.x = 200.;
+        app.update();
+
+        let aabb = app.world.resource::<SpatialHash>().aabb(collider).unwrap();
+        assert_eq!(aabb.center(), Vec2::new(300., 10.));
+    }
+
+    #[test]
+    fn spatial_hash_forgets_despawned_colliders() {
+        let (mut app, train, collider, body) = setup();
+        assert!(app.world.resource::<SpatialHash>().aabb(body).is_some());
+
+        app.world.entity_mut(train).despawn_recursive();
+        app.update();
+
+        let spatial_hash = app.world.resource::<SpatialHash>();
+        assert_eq!(spatial_hash.aabb(collider), None);
+        assert!(spatial_hash.aabb(body).is_some());
+    }
+
+    #[test]
+    fn collisions_use_the_moved_collider() {
+        let (mut app, train, collider, body) = setup();
+        assert!(collision_events(&app).is_empty());
+        assert_eq!(**app.world.get::<GroundedOn>(body).unwrap(), None);
+
+        app.world.get_mut::<Transform>(train).unwrap().translation.x = 200.;
+        app.update();
+
+        let events = collision_events(&app);
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].entity, events[0].other), (body, collider));
+        assert_eq!(events[0].side, Collision::Top);
+        assert_eq!(**app.world.get::<GroundedOn>(body).unwrap(), Some(collider));
+    }
+
+    #[test]
+    fn bodies_walk_up_slopes() {
+        let mut app = app();
+        // surface along y = x / 2, from (-64, -32) to (64, 32)
+        let slope = app
+            .world
+            .spawn((
+                TransformBundle::default(),
+                Collider::Slope {
+                    size: Vec2::new(128., 64.),
+                    rises_right: true,
+                },
+            ))
+            .id();
+        let body = falling_body(&mut app, Collider::Circle(8.), Vec2::new(-40., -10.5));
+
+        let normal = Vec2::new(-1., 2.).normalize();
+        for _ in 0..40 {
+            // walking right while gravity pulls down
+            let mut transform = app.world.get_mut::<Transform>(body).unwrap();
+            transform.translation += Vec3::new(2., -1., 0.);
+            app.world.get_mut::<Velocity>(body).unwrap().y = -1.;
+            app.update();
+
+            assert_eq!(**app.world.get::<GroundedOn>(body).unwrap(), Some(slope));
+            let events = collision_events(&app);
+            assert_eq!(events.last().unwrap().side, Collision::Top);
+            // lifted back onto the surface instead of sinking into it
+            let position = app.world.get::<Transform>(body).unwrap().translation;
+            let height = position.truncate().dot(normal);
+            assert!(height > 3., "sank into the slope at {:?}", position);
         }
-    } else {
-        None
+
+        let position = app.world.get::<Transform>(body).unwrap().translation;
+        assert!(position.y > 25., "didn't climb the slope: {:?}", position);
     }
 }
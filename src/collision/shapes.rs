@@ -0,0 +1,324 @@
+use bevy::{math::Rect, prelude::*, sprite::collide_aabb::Collision};
+
+/// Contacts with a normal steeper than this are treated as ground (or ceiling).
+pub const WALKABLE_NORMAL: f32 = 0.5;
+
+/// Convex shape used by the narrowphase: a convex core (a point, a segment or a
+/// polygon) inflated by `radius`, which covers circles and capsules too.
+#[derive(Debug, Clone)]
+pub struct Shape {
+    pub points: Vec<Vec2>,
+    pub radius: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Contact {
+    /// direction pushing the first shape out of the second one
+    pub normal: Vec2,
+    /// penetration depth along `normal`, always positive
+    pub depth: f32,
+}
+
+impl Contact {
+    /// Side of the second shape that the first one hit.
+    pub fn side(&self) -> Collision {
+        if self.normal.y >= WALKABLE_NORMAL {
+            Collision::Top
+        } else if self.normal.y <= -WALKABLE_NORMAL {
+            Collision::Bottom
+        } else if self.normal.x < 0. {
+            Collision::Left
+        } else {
+            Collision::Right
+        }
+    }
+}
+
+impl Shape {
+    pub fn inflate(mut self, amount: f32) -> Self {
+        self.radius += amount;
+        self
+    }
+
+    pub fn translate(mut self, offset: Vec2) -> Self {
+        for point in &mut self.points {
+            *point += offset;
+        }
+        self
+    }
+
+    pub fn aabb(&self) -> Rect {
+        let (min, max) = self.points.iter().fold(
+            (Vec2::splat(f32::INFINITY), Vec2::splat(f32::NEG_INFINITY)),
+            |(min, max), point| (min.min(*point), max.max(*point)),
+        );
+        Rect::from_corners(min - self.radius, max + self.radius)
+    }
+
+    fn edge_normals(&self) -> impl Iterator<Item = Vec2> + '_ {
+        let count = if self.points.len() > 1 {
+            self.points.len()
+        } else {
+            0
+        };
+        (0..count).filter_map(|i| {
+            let edge = self.points[(i + 1) % self.points.len()] - self.points[i];
+            edge.perp().try_normalize()
+        })
+    }
+
+    fn project(&self, axis: Vec2) -> (f32, f32) {
+        let (min, max) = self
+            .points
+            .iter()
+            .map(|point| point.dot(axis))
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), d| {
+                (min.min(d), max.max(d))
+            });
+        (min - self.radius, max + self.radius)
+    }
+}
+
+/// Separating axis test between two convex shapes in world space.
+/// Candidate axes are the edge normals of both cores, plus the directions between
+/// their vertices when rounded shapes are involved (closest features of a circle).
+pub fn collide(a: &Shape, b: &Shape) -> Option<Contact> {
+    let mut axes: Vec<Vec2> = a.edge_normals().chain(b.edge_normals()).collect();
+    if a.radius > 0. || b.radius > 0. {
+        for a_point in &a.points {
+            for b_point in &b.points {
+                if let Some(axis) = (*a_point - *b_point).try_normalize() {
+                    axes.push(axis);
+                }
+            }
+        }
+    }
+    if axes.is_empty() {
+        // concentric circles
+        axes.push(Vec2::Y);
+    }
+
+    let mut contact: Option<Contact> = None;
+    for axis in axes {
+        let (a_min, a_max) = a.project(axis);
+        let (b_min, b_max) = b.project(axis);
+
+        let push_positive = b_max - a_min;
+        let push_negative = a_max - b_min;
+        let depth = push_positive.min(push_negative);
+        if depth <= 0. {
+            return None;
+        }
+
+        if contact.map_or(true, |contact| depth < contact.depth) {
+            let normal = if push_positive < push_negative {
+                axis
+            } else {
+                -axis
+            };
+            contact = Some(Contact { normal, depth });
+        }
+    }
+    contact
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SweepHit {
+    /// fraction of the motion travelled before touching, from 0 to 1
+    pub time: f32,
+    /// direction pushing the moving box out of the other one
+    pub normal: Vec2,
+}
+
+/// Time of impact of box `a` moving by `motion` against the static box `b`.
+/// Boxes already overlapping are left to the discrete test and return `None`.
+pub fn sweep_aabb(a: Rect, motion: Vec2, b: Rect) -> Option<SweepHit> {
+    // time range where the boxes overlap on a single axis
+    let axis_times = |a_min: f32, a_max: f32, b_min: f32, b_max: f32, motion: f32| {
+        if motion == 0. {
+            return (a_max > b_min && a_min < b_max).then_some((f32::NEG_INFINITY, f32::INFINITY));
+        }
+        let (entry, exit) = if motion > 0. {
+            (b_min - a_max, b_max - a_min)
+        } else {
+            (b_max - a_min, b_min - a_max)
+        };
+        Some((entry / motion, exit / motion))
+    };
+
+    let (x_entry, x_exit) = axis_times(a.min.x, a.max.x, b.min.x, b.max.x, motion.x)?;
+    let (y_entry, y_exit) = axis_times(a.min.y, a.max.y, b.min.y, b.max.y, motion.y)?;
+
+    let entry = x_entry.max(y_entry);
+    let exit = x_exit.min(y_exit);
+    if entry > exit || !(0. ..1.).contains(&entry) {
+        return None;
+    }
+
+    let normal = if x_entry > y_entry {
+        Vec2::new(-motion.x.signum(), 0.)
+    } else {
+        Vec2::new(0., -motion.y.signum())
+    };
+    Some(SweepHit {
+        time: entry,
+        normal,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::Collider;
+
+    fn shape(collider: Collider, x: f32, y: f32) -> Shape {
+        collider.shape(&Transform::from_xyz(x, y, 0.))
+    }
+
+    fn quad(width: f32, height: f32) -> Collider {
+        Collider::Quad(Vec2::new(width, height))
+    }
+
+    fn capsule() -> Collider {
+        Collider::Capsule {
+            half_height: 4.,
+            radius: 6.,
+        }
+    }
+
+    fn assert_contact(contact: Option<Contact>, normal: Vec2, depth: f32) {
+        let contact = contact.expect("shapes should overlap");
+        assert!(
+            contact.normal.abs_diff_eq(normal, 1e-4),
+            "normal {:?}, expected {:?}",
+            contact.normal,
+            normal
+        );
+        assert!(
+            (contact.depth - depth).abs() < 1e-4,
+            "depth {}, expected {}",
+            contact.depth,
+            depth
+        );
+    }
+
+    #[test]
+    fn quads() {
+        let ground = shape(quad(64., 16.), 0., 0.);
+        assert!(collide(&shape(quad(16., 16.), 0., 20.), &ground).is_none());
+        // touching isn't overlapping
+        assert!(collide(&shape(quad(16., 16.), 0., 16.), &ground).is_none());
+        assert_contact(
+            collide(&shape(quad(16., 16.), 0., 15.), &ground),
+            Vec2::Y,
+            1.,
+        );
+        assert_contact(
+            collide(&shape(quad(16., 16.), 38., 0.), &ground),
+            Vec2::X,
+            2.,
+        );
+    }
+
+    #[test]
+    fn circles() {
+        let circle = shape(Collider::Circle(6.), 0., 0.);
+        assert!(collide(&shape(Collider::Circle(4.), 11., 0.), &circle).is_none());
+        assert!(collide(&shape(Collider::Circle(4.), 0., 10.), &circle).is_none());
+        assert_contact(
+            collide(&shape(Collider::Circle(4.), 3., 4.), &circle),
+            Vec2::new(0.6, 0.8),
+            5.,
+        );
+    }
+
+    #[test]
+    fn concentric_circles() {
+        let contact = collide(
+            &shape(Collider::Circle(4.), 5., 5.),
+            &shape(Collider::Circle(6.), 5., 5.),
+        )
+        .unwrap();
+        assert_eq!(contact.normal.x, 0.);
+        assert_eq!(contact.normal.y.abs(), 1.);
+        assert_eq!(contact.depth, 10.);
+    }
+
+    #[test]
+    fn capsules() {
+        let ground = shape(quad(64., 16.), 0., 0.);
+        // the capsule reaches 10 under its center
+        assert!(collide(&shape(capsule(), 0., 18.1), &ground).is_none());
+        assert_contact(collide(&shape(capsule(), 0., 17.), &ground), Vec2::Y, 1.);
+
+        let circle = shape(Collider::Circle(4.), 9., 0.);
+        assert!(collide(&shape(capsule(), -1.1, 0.), &circle).is_none());
+        // against the straight side, not the rounded caps
+        let contact = collide(&shape(capsule(), 0., 0.), &circle);
+        assert_contact(contact, Vec2::NEG_X, 1.);
+        assert_eq!(contact.unwrap().side(), Collision::Left);
+    }
+
+    #[test]
+    fn slopes() {
+        let slope = shape(
+            Collider::Slope {
+                size: Vec2::new(128., 64.),
+                rises_right: true,
+            },
+            0.,
+            0.,
+        );
+        let normal = Vec2::new(-1., 2.).normalize();
+
+        let above = normal * 10.;
+        assert!(collide(&shape(Collider::Circle(8.), above.x, above.y), &slope).is_none());
+
+        let sinking = normal * 6.;
+        let contact = collide(&shape(Collider::Circle(8.), sinking.x, sinking.y), &slope);
+        assert_contact(contact, normal, 2.);
+        assert_eq!(contact.unwrap().side(), Collision::Top);
+    }
+
+    #[test]
+    fn polygons() {
+        let diamond = Collider::Polygon(vec![
+            Vec2::new(0., -10.),
+            Vec2::new(10., 0.),
+            Vec2::new(0., 10.),
+            Vec2::new(-10., 0.),
+        ]);
+        let ground = shape(quad(20., 10.), 0., 0.);
+
+        assert!(collide(&shape(diamond.clone(), 0., 16.), &ground).is_none());
+        assert!(collide(&shape(diamond.clone(), 0., 15.), &ground).is_none());
+        assert_contact(
+            collide(&shape(diamond.clone(), 0., 14.), &ground),
+            Vec2::Y,
+            1.,
+        );
+        // the corner of the quad cuts into the lower left edge of the diamond
+        assert!(collide(&shape(diamond.clone(), 16., 10.), &ground).is_none());
+        assert_contact(
+            collide(&shape(diamond, 14., 10.), &ground),
+            Vec2::ONE.normalize(),
+            1. / 2f32.sqrt(),
+        );
+    }
+
+    #[test]
+    fn sides() {
+        let side = |x: f32, y: f32| {
+            Contact {
+                normal: Vec2::new(x, y).normalize(),
+                depth: 1.,
+            }
+            .side()
+        };
+        assert_eq!(side(0., 1.), Collision::Top);
+        assert_eq!(side(-1., 2.), Collision::Top);
+        assert_eq!(side(0., -1.), Collision::Bottom);
+        assert_eq!(side(-1., 0.), Collision::Left);
+        assert_eq!(side(1., 0.1), Collision::Right);
+    }
+}
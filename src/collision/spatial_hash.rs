@@ -0,0 +1,400 @@
+use bevy::{
+    math::Rect,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::{world_transform, Collider};
+
+/// Uniform grid broadphase of every `Collider` in world space.
+#[derive(Resource)]
+pub struct SpatialHash {
+    cell_size: f32,
+    cells: HashMap<IVec2, Vec<Entity>>,
+    aabbs: HashMap<Entity, Rect>,
+}
+
+impl Default for SpatialHash {
+    fn default() -> Self {
+        Self::new(64.)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub entity: Entity,
+    pub distance: f32,
+    pub point: Vec2,
+}
+
+impl SpatialHash {
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::default(),
+            aabbs: HashMap::default(),
+        }
+    }
+
+    pub fn aabb(&self, entity: Entity) -> Option<Rect> {
+        self.aabbs.get(&entity).copied()
+    }
+
+    pub fn insert(&mut self, entity: Entity, aabb: Rect) {
+        self.remove(entity);
+        for cell in self.cells_in(aabb) {
+            self.cells.entry(cell).or_default().push(entity);
+        }
+        self.aabbs.insert(entity, aabb);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        let Some(aabb) = self.aabbs.remove(&entity) else {
+            return;
+        };
+        for cell in self.cells_in(aabb) {
+            if let Some(entities) = self.cells.get_mut(&cell) {
+                entities.retain(|e| *e != entity);
+                if entities.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Colliders overlapping `aabb`, sorted so iteration order doesn't depend on the hash.
+    pub fn colliders_in_aabb(&self, aabb: Rect) -> Vec<Entity> {
+        let mut entities: Vec<Entity> = self
+            .cells_in(aabb)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .filter(|entity| !self.aabbs[entity].intersect(aabb).is_empty())
+            .collect();
+        entities.sort_unstable();
+        entities.dedup();
+        entities
+    }
+
+    /// First collider hit by the ray for which `filter` returns true. The ray stops
+    /// once it leaves the occupied cells, so `max_distance` can be infinite.
+    pub fn raycast(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_distance: f32,
+        filter: impl Fn(Entity) -> bool,
+    ) -> Option<RayHit> {
+        let direction = direction.try_normalize()?;
+        let inv_direction = direction.recip();
+
+        // walk the grid cell by cell along the ray (Amanatides & Woo)
+        let mut cell = self.cell(origin);
+        let step = direction.signum().as_ivec2();
+        let delta = (self.cell_size * inv_direction).abs();
+        let next_boundary = (cell + step.max(IVec2::ZERO)).as_vec2() * self.cell_size;
+        let mut t_max = Vec2::select(
+            direction.cmpeq(Vec2::ZERO),
+            Vec2::splat(f32::INFINITY),
+            (next_boundary - origin) * inv_direction,
+        );
+
+        // nothing can be hit past the occupied cells in the direction of the ray
+        let (min_cell, max_cell) = self
+            .cells
+            .keys()
+            .fold((IVec2::MAX, IVec2::MIN), |(min_cell, max_cell), cell| {
+                (min_cell.min(*cell), max_cell.max(*cell))
+            });
+        let forward = step.cmpge(IVec2::ZERO);
+        let backward = step.cmple(IVec2::ZERO);
+
+        let mut best: Option<RayHit> = None;
+        loop {
+            if ((cell.cmpgt(max_cell) & forward) | (cell.cmplt(min_cell) & backward)).any() {
+                return best;
+            }
+
+            for entity in self.cells.get(&cell).into_iter().flatten() {
+                if !filter(*entity) {
+                    continue;
+                }
+                let Some(distance) = ray_aabb(origin, inv_direction, self.aabbs[entity]) else {
+                    continue;
+                };
+                if distance <= max_distance && best.map_or(true, |hit| distance < hit.distance) {
+                    best = Some(RayHit {
+                        entity: *entity,
+                        distance,
+                        point: origin + direction * distance,
+                    });
+                }
+            }
+
+            let t = t_max.min_element();
+            // anything closer than the next cell can't be beaten by later cells
+            if best.map_or(false, |hit| hit.distance <= t) || t > max_distance {
+                return best;
+            }
+
+            if t_max.x < t_max.y {
+                cell.x += step.x;
+                t_max.x += delta.x;
+            } else {
+                cell.y += step.y;
+                t_max.y += delta.y;
+            }
+        }
+    }
+
+    fn cell(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    fn cells_in(&self, aabb: Rect) -> impl Iterator<Item = IVec2> {
+        let min = self.cell(aabb.min);
+        let max = self.cell(aabb.max);
+        (min.x..=max.x).flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+    }
+}
+
+/// Distance along the ray to `aabb` using the slab method.
+fn ray_aabb(origin: Vec2, inv_direction: Vec2, aabb: Rect) -> Option<f32> {
+    let t1 = (aabb.min - origin) * inv_direction;
+    let t2 = (aabb.max - origin) * inv_direction;
+    let t_near = t1.min(t2).max_element();
+    let t_far = t1.max(t2).min_element();
+
+    (t_near <= t_far && t_far >= 0.).then_some(t_near.max(0.))
+}
+
+/// Re-inserts colliders whose shape or world transform changed since the last run,
+/// moving an entity moves the colliders of all its descendants.
+pub fn update_spatial_hash(
+    mut spatial_hash: ResMut<SpatialHash>,
+    changed_query: Query<Entity, Or<(Changed<Collider>, Changed<Transform>, Changed<Parent>)>>,
+    collider_query: Query<&Collider>,
+    children_query: Query<&Children>,
+    transform_query: Query<&Transform>,
+    parent_query: Query<&Parent>,
+) {
+    let mut dirty = HashSet::new();
+    for entity in &changed_query {
+        // the descendants were already visited through a moved ancestor
+        if !dirty.insert(entity) {
+            continue;
+        }
+        dirty.extend(children_query.iter_descendants(entity));
+    }
+
+    for entity in dirty {
+        let Ok(collider) = collider_query.get(entity) else {
+            continue;
+        };
+        let transform =
+            world_transform(entity, &transform_query, &parent_query).compute_transform();
+        spatial_hash.insert(entity, collider.aabb(&transform));
+    }
+}
+
+/// Forgets despawned colliders. Runs every frame as well as before each broadphase
+/// update, so the colliders despawned in `Update` (e.g. the wagons on reload) aren't
+/// missed when no fixed step runs in that frame.
+pub fn remove_despawned_colliders(
+    mut spatial_hash: ResMut<SpatialHash>,
+    mut removed_colliders: RemovedComponents<Collider>,
+) {
+    for entity in removed_colliders.read() {
+        spatial_hash.remove(entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::simulation::SimulationRng;
+
+    use super::*;
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect::new(x, y, x + width, y + height)
+    }
+
+    /// Three boxes along the x axis: a small one, one spanning several cells, and
+    /// one further away.
+    fn spatial_hash() -> (SpatialHash, [Entity; 3]) {
+        let entities = [0, 1, 2].map(Entity::from_raw);
+        let mut spatial_hash = SpatialHash::new(64.);
+        spatial_hash.insert(entities[0], rect(0., 0., 16., 16.));
+        spatial_hash.insert(entities[1], rect(32., -100., 200., 200.));
+        spatial_hash.insert(entities[2], rect(400., 0., 16., 16.));
+        (spatial_hash, entities)
+    }
+
+    #[test]
+    fn colliders_in_aabb_returns_overlaps_once() {
+        let (spatial_hash, [small, large, far]) = spatial_hash();
+
+        assert_eq!(
+            spatial_hash.colliders_in_aabb(rect(-10., -10., 300., 20.)),
+            vec![small, large]
+        );
+        assert_eq!(
+            spatial_hash.colliders_in_aabb(rect(390., -10., 20., 20.)),
+            vec![far]
+        );
+        // same cell as the small box, but not overlapping it
+        assert!(spatial_hash
+            .colliders_in_aabb(rect(20., 20., 8., 8.))
+            .is_empty());
+    }
+
+    #[test]
+    fn colliders_in_aabb_follows_insert_and_remove() {
+        let (mut spatial_hash, [small, large, far]) = spatial_hash();
+
+        spatial_hash.insert(small, rect(500., 0., 16., 16.));
+        assert!(spatial_hash
+            .colliders_in_aabb(rect(-10., -10., 20., 20.))
+            .is_empty());
+        assert_eq!(
+            spatial_hash.colliders_in_aabb(rect(390., -10., 200., 20.)),
+            vec![small, far]
+        );
+
+        spatial_hash.remove(large);
+        assert_eq!(spatial_hash.aabb(large), None);
+        assert!(spatial_hash
+            .colliders_in_aabb(rect(32., -100., 200., 200.))
+            .is_empty());
+    }
+
+    #[test]
+    fn raycast_hits_the_nearest_collider() {
+        let (spatial_hash, [small, large, far]) = spatial_hash();
+
+        let hit = spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::X, 1000., |_| true)
+            .unwrap();
+        assert_eq!(hit.entity, small);
+        assert_eq!(hit.distance, 100.);
+        assert_eq!(hit.point, Vec2::new(0., 8.));
+
+        let hit = spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::X, 1000., |entity| {
+                entity != small
+            })
+            .unwrap();
+        assert_eq!(hit.entity, large);
+        assert_eq!(hit.distance, 132.);
+
+        // backwards, from past the far box
+        let hit = spatial_hash
+            .raycast(Vec2::new(600., 8.), Vec2::NEG_X, f32::INFINITY, |_| true)
+            .unwrap();
+        assert_eq!(hit.entity, far);
+        assert_eq!(hit.distance, 184.);
+    }
+
+    #[test]
+    fn raycast_misses() {
+        let (spatial_hash, _) = spatial_hash();
+
+        // too short
+        assert!(spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::X, 50., |_| true)
+            .is_none());
+        // pointing away
+        assert!(spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::NEG_X, 1000., |_| true)
+            .is_none());
+        // no direction
+        assert!(spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::ZERO, 1000., |_| true)
+            .is_none());
+        // without a limit, past every collider
+        assert!(spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::NEG_X, f32::INFINITY, |_| true)
+            .is_none());
+        assert!(spatial_hash
+            .raycast(Vec2::new(-100., 8.), Vec2::Y, f32::INFINITY, |_| true)
+            .is_none());
+        assert!(SpatialHash::default()
+            .raycast(Vec2::ZERO, Vec2::ONE, f32::INFINITY, |_| true)
+            .is_none());
+    }
+
+    #[test]
+    fn raycast_from_inside_and_diagonally() {
+        let (spatial_hash, [small, large, _]) = spatial_hash();
+
+        let hit = spatial_hash
+            .raycast(Vec2::new(100., 0.), Vec2::Y, 1000., |_| true)
+            .unwrap();
+        assert_eq!(hit.entity, large);
+        assert_eq!(hit.distance, 0.);
+
+        let hit = spatial_hash
+            .raycast(Vec2::new(-100., -100.), Vec2::ONE, 1000., |_| true)
+            .unwrap();
+        assert_eq!(hit.entity, small);
+        assert!((hit.distance - 100. * 2f32.sqrt()).abs() < 0.01);
+    }
+
+    /// Compares the grid to scanning every collider, on a level the size of a long
+    /// train. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmark_against_naive_scan() {
+        let mut rng = SimulationRng::new(0);
+        let mut spatial_hash = SpatialHash::default();
+        for index in 0..10_000 {
+            let position = Vec2::new(rng.next_f32() * 100_000., rng.next_f32() * 1_000.);
+            let size = Vec2::new(16. + rng.next_f32() * 256., 16. + rng.next_f32() * 64.);
+            spatial_hash.insert(
+                Entity::from_raw(index),
+                Rect::from_corners(position, position + size),
+            );
+        }
+        let queries: Vec<Rect> = (0..10_000)
+            .map(|_| {
+                let position = Vec2::new(rng.next_f32() * 100_000., rng.next_f32() * 1_000.);
+                Rect::from_center_size(position, Vec2::splat(48.))
+            })
+            .collect();
+
+        let start = Instant::now();
+        let grid: Vec<Vec<Entity>> = queries
+            .iter()
+            .map(|query| spatial_hash.colliders_in_aabb(*query))
+            .collect();
+        let grid_time = start.elapsed();
+
+        let start = Instant::now();
+        let naive: Vec<Vec<Entity>> = queries
+            .iter()
+            .map(|query| {
+                let mut entities: Vec<Entity> = spatial_hash
+                    .aabbs
+                    .iter()
+                    .filter(|(_, aabb)| !aabb.intersect(*query).is_empty())
+                    .map(|(entity, _)| *entity)
+                    .collect();
+                entities.sort_unstable();
+                entities
+            })
+            .collect();
+        let naive_time = start.elapsed();
+
+        assert_eq!(grid, naive);
+        println!(
+            "{} queries over {} colliders: grid {:?}, naive scan {:?}",
+            queries.len(),
+            spatial_hash.aabbs.len(),
+            grid_time,
+            naive_time
+        );
+        assert!(grid_time < naive_time);
+    }
+}
@@ -2,6 +2,9 @@
 
 use bevy::prelude::*;
 
+use crate::collision::{platform_velocity, Dynamic, GroundedOn};
+
+/// Units per second, integrated with the fixed timestep in `apply_velocity`.
 #[derive(Component, Deref, DerefMut)]
 pub struct Velocity(Vec2);
 
@@ -19,10 +22,29 @@ impl Default for Gravity {
     }
 }
 
+/// Per body changes to the global `Gravity`, e.g. the player falling faster than it rises.
+#[derive(Component, Clone, Copy)]
+pub struct BodyGravity {
+    pub scale: f32,
+    /// maximum falling speed
+    pub terminal_velocity: f32,
+}
+
+impl Default for BodyGravity {
+    fn default() -> Self {
+        Self {
+            scale: 1.,
+            terminal_velocity: f32::INFINITY,
+        }
+    }
+}
+
 // train force Resource
 #[derive(Debug, Resource)]
 pub struct TrainForce {
+    /// external force applied to the train this tick, reset after `drive_train`
     pub force: f32,
+    /// resulting acceleration of the train in the last tick
     pub acceleration: f32,
 }
 
@@ -35,16 +57,80 @@ impl Default for TrainForce {
     }
 }
 
-pub fn apply_velocity(mut query: Query<(&mut Transform, &Velocity)>, time: Res<Time>) {
-    for (mut transform, velocity) in &mut query {
-        transform.translation.x += velocity.x;
-        transform.translation.y += velocity.y;
+pub fn apply_velocity(
+    mut query: Query<(&mut Transform, &Velocity, Option<&GroundedOn>)>,
+    platform_query: Query<&Velocity>,
+    parent_query: Query<&Parent>,
+    time: Res<Time>,
+) {
+    for (mut transform, velocity, grounded_on) in &mut query {
+        // bodies standing on a moving collider are carried along with it
+        let platform = grounded_on
+            .and_then(|grounded_on| **grounded_on)
+            .map_or(Vec2::ZERO, |ground| {
+                platform_velocity(ground, &platform_query, &parent_query)
+            });
+
+        let delta = time.delta_seconds();
+        transform.translation.x += (velocity.x + platform.x) * delta;
+        transform.translation.y += (velocity.y + platform.y) * delta;
+    }
+}
+
+pub fn apply_gravity(
+    mut query: Query<(&mut Velocity, Option<&BodyGravity>), With<Dynamic>>,
+    gravity: Res<Gravity>,
+    time: Res<Time>,
+) {
+    for (mut velocity, body_gravity) in &mut query {
+        let body_gravity = body_gravity.copied().unwrap_or_default();
+        velocity.y -= gravity.0 * body_gravity.scale * time.delta_seconds();
+        velocity.y = velocity.y.max(-body_gravity.terminal_velocity);
+    }
+}
+
+/// Smooths the rendering of entities moved in `FixedUpdate`: the simulated
+/// translation is kept aside and `Transform` is interpolated between the last two
+/// fixed steps while rendering.
+#[derive(Component, Default)]
+pub struct Interpolated {
+    previous: Option<Vec3>,
+    current: Option<Vec3>,
+}
+
+impl Interpolated {
+    /// Translation at the end of the last fixed step.
+    pub fn simulated(&self) -> Option<Vec3> {
+        self.current
+    }
+}
+
+/// First system of the fixed step, puts back the simulated translation.
+pub fn restore_simulated_translation(mut query: Query<(&mut Transform, &mut Interpolated)>) {
+    for (mut transform, mut interpolated) in &mut query {
+        if let Some(current) = interpolated.current {
+            transform.translation = current;
+        }
+        interpolated.previous = Some(transform.translation);
+    }
+}
+
+/// Last system of the fixed step, keeps the simulated translation.
+pub fn record_simulated_translation(mut query: Query<(&Transform, &mut Interpolated)>) {
+    for (transform, mut interpolated) in &mut query {
+        interpolated.current = Some(transform.translation);
     }
 }
 
-pub fn apply_gravity(mut query: Query<&mut Velocity>, gravity: Res<Gravity>, time: Res<Time>) {
-    for mut velocity in &mut query {
-        velocity.y -= gravity.0 * time.delta_seconds();
+pub fn interpolate_translation(
+    mut query: Query<(&mut Transform, &Interpolated)>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    let alpha = fixed_time.overstep_percentage();
+    for (mut transform, interpolated) in &mut query {
+        if let (Some(previous), Some(current)) = (interpolated.previous, interpolated.current) {
+            transform.translation = previous.lerp(current, alpha);
+        }
     }
 }
 
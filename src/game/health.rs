@@ -0,0 +1,88 @@
+use bevy::prelude::*;
+
+use crate::collision::CollisionEvent;
+
+#[derive(Component, Reflect)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+    /// seconds without taking damage after a hit
+    pub invulnerability: f32,
+    /// invulnerability left from the last hit
+    pub invulnerable_for: f32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            current: 3.,
+            max: 3.,
+            invulnerability: 1.5,
+            invulnerable_for: 0.,
+        }
+    }
+}
+
+impl Health {
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0.
+    }
+
+    pub fn is_invulnerable(&self) -> bool {
+        self.invulnerable_for > 0.
+    }
+
+    /// Back to full health, invulnerable for a while.
+    pub fn restore(&mut self) {
+        self.current = self.max;
+        self.invulnerable_for = self.invulnerability;
+    }
+}
+
+/// Collider that damages what it touches, e.g. obstacles, enemies or kill zones.
+#[derive(Component)]
+pub struct Hazard {
+    pub damage: f32,
+}
+
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Damage {
+    pub entity: Entity,
+    pub amount: f32,
+}
+
+pub fn damage_from_hazards(
+    mut collision_events: EventReader<CollisionEvent>,
+    hazard_query: Query<&Hazard>,
+    mut damage_events: EventWriter<Damage>,
+) {
+    for collision in collision_events.read() {
+        if let Ok(hazard) = hazard_query.get(collision.other) {
+            damage_events.send(Damage {
+                entity: collision.entity,
+                amount: hazard.damage,
+            });
+        }
+    }
+}
+
+pub fn apply_damage(
+    mut damage_events: EventReader<Damage>,
+    mut query: Query<&mut Health>,
+    time: Res<Time>,
+) {
+    for mut health in &mut query {
+        health.invulnerable_for = (health.invulnerable_for - time.delta_seconds()).max(0.);
+    }
+
+    for damage in damage_events.read() {
+        let Ok(mut health) = query.get_mut(damage.entity) else {
+            continue;
+        };
+        if health.is_invulnerable() || health.is_dead() {
+            continue;
+        }
+        health.current = (health.current - damage.amount).max(0.);
+        health.invulnerable_for = health.invulnerability;
+    }
+}
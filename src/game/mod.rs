@@ -1,6 +1,9 @@
 pub mod common;
+pub mod health;
 pub mod player;
 pub mod train;
+pub mod wagon;
 
 pub use common::*;
+pub use health::*;
 pub use player::*;
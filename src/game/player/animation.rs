@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use bevy::prelude::*;
 
 #[derive(Component, Deref, DerefMut)]
@@ -20,6 +22,8 @@ pub struct AnimationStraight {
     pub last: usize,
     pub repeat_from: Option<usize>,
     pub cool_down: Option<f32>,
+    /// frame where the player applies force (e.g. pushing the wagon)
+    pub impulse_frame: Option<usize>,
 }
 
 impl Default for AnimationStraight {
@@ -30,10 +34,43 @@ impl Default for AnimationStraight {
             last: 0,
             repeat_from: None,
             cool_down: None,
+            impulse_frame: None,
         }
     }
 }
 
+/// Stretches the sprite and eases it back to its normal scale, e.g. when landing.
+#[derive(Component)]
+pub struct Squash {
+    /// scale added at the start, e.g. wider and shorter on landing
+    amount: Vec2,
+    timer: Timer,
+}
+
+impl Default for Squash {
+    fn default() -> Self {
+        Self {
+            amount: Vec2::ZERO,
+            timer: Timer::from_seconds(0., TimerMode::Once),
+        }
+    }
+}
+
+impl Squash {
+    pub fn start(&mut self, amount: Vec2, seconds: f32) {
+        self.amount = amount;
+        self.timer = Timer::from_seconds(seconds, TimerMode::Once);
+    }
+
+    pub fn scale(&self) -> Vec2 {
+        Vec2::ONE + self.amount * self.timer.percent_left()
+    }
+
+    pub fn tick(&mut self, delta: Duration) {
+        self.timer.tick(delta);
+    }
+}
+
 #[derive(Component)]
 pub enum AnimationIndices {
     Straight(AnimationStraight),
@@ -49,4 +86,10 @@ impl AnimationIndices {
             Self::Straight(anim) => anim.cool_down,
         }
     }
+
+    pub fn get_impulse_frame(&self) -> Option<usize> {
+        match self {
+            Self::Straight(anim) => anim.impulse_frame,
+        }
+    }
 }
@@ -1,18 +1,29 @@
 mod animation;
 mod state_machine;
 
-use state_machine::PlayerState;
+pub use state_machine::{PlayerEvent, PlayerMachine, PlayerState};
 
-use bevy::{prelude::*, utils::petgraph::matrix_graph::Zero};
+use std::collections::BTreeSet;
 
-use crate::collision::Collider;
+use bevy::{prelude::*, sprite::collide_aabb::Collision, utils::petgraph::matrix_graph::Zero};
 
-use self::{
-    animation::{AnimationIndices, AnimationState, AnimationTimer},
-    state_machine::PlayerEvent,
+use crate::{
+    collision::{
+        platform_velocity, Collider, CollisionEnded, CollisionGroups, CollisionLayers,
+        CollisionOngoing, CollisionStarted, DropThrough, Dynamic, DynamicBundle, GroundedOn,
+        OneWay, Sensor, SpatialHash,
+    },
+    input::{Action, PlayerInput, Rumble},
+    state_machine::StateEntered,
 };
 
-use super::{move_towards, Gravity, Velocity};
+use self::animation::{AnimationIndices, AnimationState, AnimationTimer, Squash};
+
+use super::{
+    move_towards,
+    train::{Checkpoint, TrackGround, Wagon},
+    BodyGravity, Gravity, Health, Interpolated, TrainForce, Velocity,
+};
 
 #[derive(Component, Reflect)]
 pub struct Player {
@@ -22,10 +33,79 @@ pub struct Player {
     pub time_jump_peak: f32,
     pub jump_height: f32,
     pub jump_velocity: f32,
+    /// seconds after walking off a ledge during which the player can still jump
+    pub coyote_time: f32,
+    /// seconds a jump pressed before landing is kept until the player can jump
+    pub jump_buffer: f32,
+    /// ratio of the upwards velocity kept when jump is released early
+    pub jump_cut: f32,
+    /// gravity multiplier while falling
+    pub fall_gravity_multiplier: f32,
+    /// maximum falling speed
+    pub terminal_velocity: f32,
+    /// seconds without gravity at the peak of a jump held until the end
+    pub apex_hang_time: f32,
+    /// falling faster than this stuns the player on landing
+    pub hard_landing_speed: f32,
+    /// running speed on the track ground, fast enough to catch up with the train
+    pub catch_up_speed: f32,
+    /// falling this far behind the last wagon costs a life
+    pub left_behind_distance: f32,
+    /// change in train speed applied on every push/pull animation impulse
+    pub push_impulse: f32,
+    pub pull_impulse: f32,
 }
 
-#[derive(Component, Deref, DerefMut)]
-pub struct StateTriggerTimer(Option<Timer>);
+#[derive(Component, Reflect)]
+pub struct Stamina {
+    pub current: f32,
+    pub max: f32,
+    /// stamina used per second while pushing or pulling
+    pub drain: f32,
+    /// stamina recovered per second while resting
+    pub recovery: f32,
+    /// once exhausted, ratio of `max` needed to push or pull again
+    pub rested_ratio: f32,
+}
+
+impl Default for Stamina {
+    fn default() -> Self {
+        Self {
+            current: 5.,
+            max: 5.,
+            drain: 1.,
+            recovery: 0.5,
+            rested_ratio: 0.5,
+        }
+    }
+}
+
+/// Jump timing tracked for `Player::coyote_time` and `Player::jump_buffer`.
+#[derive(Component, Default)]
+pub struct JumpTimers {
+    since_grounded: f32,
+    /// time left to use the last jump press
+    buffered: f32,
+    jump_held: bool,
+    /// rising from a jump that can still be cut short
+    jumping: bool,
+    /// time left without gravity at the apex
+    apex_hang: f32,
+    /// last falling speed before touching the ground
+    fall_speed: f32,
+}
+
+/// Last checkpoint wagon the player stood on, where they respawn.
+#[derive(Component, Default, Deref, DerefMut)]
+pub struct LastCheckpoint(Option<Entity>);
+
+/// Falling this far under the wagons kills the player.
+const FALL_OFF_DISTANCE: f32 = 128.;
+
+/// Height above the ground the player drops in from when respawning.
+const DROP_IN_HEIGHT: f32 = 32.;
+/// How far above the checkpoint to look for things standing on it.
+const DROP_IN_CLEARANCE: f32 = 256.;
 
 #[derive(Component, Deref, DerefMut)]
 pub struct AnimationCoolDownTimer(Option<(Timer, PlayerState)>);
@@ -52,11 +132,23 @@ impl AnimationCoolDownTimer {
 impl Default for Player {
     fn default() -> Self {
         Self {
-            max_speed: 2.5,
-            acceleration: 50.,
+            max_speed: 160.,
+            acceleration: 3200.,
             time_jump_peak: 0.3,
-            jump_height: 4.0,
+            jump_height: 256.,
             jump_velocity: 0.,
+            coyote_time: 0.1,
+            jump_buffer: 0.12,
+            jump_cut: 0.5,
+            fall_gravity_multiplier: 1.5,
+            terminal_velocity: 2560.,
+            apex_hang_time: 0.05,
+            // a bit faster than landing from a full jump
+            hard_landing_speed: 2304.,
+            catch_up_speed: 240.,
+            left_behind_distance: 640.,
+            push_impulse: 3.2,
+            pull_impulse: 5.12,
         }
     }
 }
@@ -69,7 +161,7 @@ pub fn setup_player(
 ) {
     let player_size = Vec2::new(22.0, 26.0);
 
-    let player_state = PlayerState::default();
+    let player_state = PlayerState::machine();
     let mut player = Player::default();
     gravity.0 = (2. * player.jump_height) / player.time_jump_peak.powi(2);
     player.jump_velocity = gravity.0 * player.time_jump_peak;
@@ -87,9 +179,25 @@ pub fn setup_player(
             },
             player,
             player_state,
-            StateTriggerTimer(None),
-            Velocity::default(), // This should be context
-            Collider::Quad(player_size),
+            Stamina::default(),
+            Health::default(),
+            LastCheckpoint::default(),
+            JumpTimers::default(),
+            BodyGravity::default(),
+            DynamicBundle::default(),
+            Interpolated::default(),
+            Collider::Capsule {
+                half_height: (player_size.y - player_size.x) / 2.,
+                radius: player_size.x / 2.,
+            },
+            CollisionGroups::new(
+                CollisionLayers::PLAYER,
+                CollisionLayers::WAGON
+                    | CollisionLayers::OBSTACLE
+                    | CollisionLayers::ENEMY
+                    | CollisionLayers::PICKUP
+                    | CollisionLayers::SENSOR,
+            ),
         ))
         .with_children(|parent| {
             parent.spawn(SpriteBundle {
@@ -106,8 +214,9 @@ pub fn setup_player(
                     ..default()
                 },
                 AnimationTimer::default(),
-                player_state.get_animation(),
+                player_state.state().get_animation(),
                 AnimationCoolDownTimer(None),
+                Squash::default(),
             ));
 
             parent.spawn(SpriteBundle {
@@ -120,97 +229,475 @@ pub fn setup_player(
 
 // TODO: try Res<PlayerState> instead of Query
 pub fn move_player(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut query: Query<(&Player, &mut PlayerState, &mut Velocity)>,
+    input: Res<PlayerInput>,
+    mut query: Query<(
+        &Player,
+        &mut PlayerMachine,
+        &mut Velocity,
+        &GroundedOn,
+        &mut DropThrough,
+        &mut JumpTimers,
+        &Children,
+    )>,
     mut sprite_query: Query<&mut TextureAtlasSprite, With<AnimationIndices>>,
+    one_way_query: Query<(), With<OneWay>>,
+    track_query: Query<(), With<TrackGround>>,
     time: Res<Time>,
 ) {
-    let (player, mut player_state, mut player_velocity) = query.single_mut();
-    let mut sprite = sprite_query.single_mut();
-    let mut direction = Vec2::ZERO;
+    let direction = input.direction();
+    let delta = time.delta_seconds();
+
+    for (
+        player,
+        mut player_state,
+        mut player_velocity,
+        grounded_on,
+        mut drop_through,
+        mut jump_timers,
+        children,
+    ) in &mut query
+    {
+        let on_ground = grounded_on.is_some() && player_velocity.y <= 0.;
+        if on_ground {
+            jump_timers.since_grounded = 0.;
+            if jump_timers.fall_speed >= player.hard_landing_speed {
+                if player_state.send(PlayerEvent::HardLand) {
+                    player_velocity.x = 0.;
+                }
+            } else {
+                player_state.send(PlayerEvent::Land);
+            }
+            jump_timers.fall_speed = 0.;
+        } else {
+            jump_timers.since_grounded += delta;
+            jump_timers.fall_speed = (-player_velocity.y).max(0.);
+            if player_velocity.y < 0. {
+                player_state.send(PlayerEvent::Fall);
+            }
+        }
+        player_state.set_flag("grounded", jump_timers.since_grounded <= player.coyote_time);
+
+        let jump_pressed = input.pressed(Action::Jump);
+        if jump_pressed && !jump_timers.jump_held {
+            jump_timers.buffered = player.jump_buffer;
+        } else {
+            jump_timers.buffered = (jump_timers.buffered - delta).max(0.);
+        }
+        jump_timers.jump_held = jump_pressed;
+
+        // fell off the train, sprint to catch up with it
+        let on_track = grounded_on.map_or(false, |ground| track_query.contains(ground));
+        if on_track && player_state.is(PlayerState::Idle) {
+            // there's no wagon to push from the track
+            player_state.cancel_trigger();
+        }
+        let max_speed = if on_track {
+            player.catch_up_speed
+        } else {
+            player.max_speed
+        };
+
+        if !direction.x.is_zero() {
+            let move_x = move_towards(
+                player_velocity.x.clone(),
+                direction.x * max_speed,
+                player.acceleration,
+                delta,
+            );
+            // TODO: refactor
+            if player_state.is(PlayerState::Moving) {
+                player_velocity.x = move_x;
+            } else if player_state.send(PlayerEvent::Move) {
+                player_velocity.x = move_x;
+            }
+
+            let mut sprites = sprite_query.iter_many_mut(children.iter());
+            while let Some(mut sprite) = sprites.fetch_next() {
+                sprite.flip_x = direction.x < 0.;
+            }
+        } else {
+            // println!("direction.x is zero {:?}", time.delta_seconds());
+        }
+
+        if direction.y < 0. {
+            match **grounded_on {
+                // standing on a one-way platform, drop through it
+                Some(ground) if one_way_query.contains(ground) => **drop_through = Some(ground),
+                _ => {
+                    player_state.send(PlayerEvent::Pull);
+                }
+            }
+        }
+
+        if jump_timers.buffered > 0. && player_state.send(PlayerEvent::Jump) {
+            player_velocity.y = player.jump_velocity;
+            jump_timers.buffered = 0.;
+            jump_timers.jumping = true;
+            // the coyote time is spent, no jumping again until landing
+            jump_timers.since_grounded = f32::INFINITY;
+        }
 
-    if keyboard_input.pressed(KeyCode::Left) {
-        direction.x -= 1.;
+        if input.pressed(Action::Push) {
+            player_state.send(PlayerEvent::Push);
+        }
+
+        if direction.length().is_zero() && player_state.can(PlayerEvent::Stop) {
+            player_state.send(PlayerEvent::Stop);
+            player_velocity.x = 0.;
+        }
     }
-    if keyboard_input.pressed(KeyCode::Right) {
-        direction.x += 1.;
+}
+
+/// Shapes the jump arc: early release cuts it short, the apex hangs for a moment
+/// and the fall is faster than the rise.
+pub fn control_jump(
+    input: Res<PlayerInput>,
+    mut query: Query<(&Player, &mut Velocity, &mut JumpTimers, &mut BodyGravity)>,
+    time: Res<Time>,
+) {
+    for (player, mut velocity, mut jump_timers, mut body_gravity) in &mut query {
+        if jump_timers.jumping {
+            if velocity.y <= 0. {
+                jump_timers.jumping = false;
+                jump_timers.apex_hang = player.apex_hang_time;
+            } else if !input.pressed(Action::Jump) {
+                jump_timers.jumping = false;
+                velocity.y *= player.jump_cut;
+            }
+        }
+
+        body_gravity.scale = if jump_timers.apex_hang > 0. {
+            0.
+        } else if velocity.y < 0. {
+            player.fall_gravity_multiplier
+        } else {
+            1.
+        };
+        body_gravity.terminal_velocity = player.terminal_velocity;
+        jump_timers.apex_hang = (jump_timers.apex_hang - time.delta_seconds()).max(0.);
     }
-    if keyboard_input.pressed(KeyCode::Up) {
-        direction.y += 1.;
+}
+
+/// Rumbles harder the faster a player lands, walking from a wagon onto the next
+/// one doesn't count as landing.
+pub fn rumble_on_landing(
+    mut started_events: EventReader<CollisionStarted>,
+    mut ongoing_events: EventReader<CollisionOngoing>,
+    mut ended_events: EventReader<CollisionEnded>,
+    query: Query<(), With<Player>>,
+    mut ground_contacts: Local<BTreeSet<(Entity, Entity)>>,
+    mut rumble_events: EventWriter<Rumble>,
+) {
+    for ended in ended_events.read() {
+        ground_contacts.remove(&(ended.entity, ended.other));
     }
-    if keyboard_input.pressed(KeyCode::Down) {
-        direction.y -= 1.;
+    // walked up onto the top or slid off it without starting a new contact
+    for ongoing in ongoing_events.read() {
+        let pair = (ongoing.entity, ongoing.other);
+        if ongoing.side == Collision::Top {
+            ground_contacts.insert(pair);
+        } else {
+            ground_contacts.remove(&pair);
+        }
     }
 
-    if !direction.x.is_zero() {
-        let delta = time.delta_seconds();
-        let move_x = move_towards(
-            player_velocity.x.clone(),
-            direction.x * player.max_speed,
-            player.acceleration,
-            delta,
-        );
-        // TODO: refactor
-        if player_state.is(PlayerState::Moving) {
-            player_velocity.x = move_x;
-        } else if player_state.transition(PlayerEvent::Move) {
-            player_velocity.x = move_x;
+    for started in started_events.read() {
+        if started.side != Collision::Top || !query.contains(started.entity) {
+            continue;
         }
+        let was_grounded = ground_contacts
+            .iter()
+            .any(|(entity, _)| *entity == started.entity);
+        ground_contacts.insert((started.entity, started.other));
+        if !was_grounded {
+            // vertical penetration, grows with the falling speed
+            let impact = started.depth / started.normal.y;
+            rumble_events.send(Rumble::new((0.2 + impact / 10.).min(0.6), 0.1));
+        }
+    }
+}
 
-        sprite.flip_x = direction.x < 0.;
-    } else {
-        // println!("direction.x is zero {:?}", time.delta_seconds());
+/// Run with `entered(PlayerState::Dead)`.
+pub fn rumble_on_death(mut rumble_events: EventWriter<Rumble>) {
+    rumble_events.send(Rumble::new(1., 0.5));
+}
+
+pub fn track_checkpoint(
+    mut query: Query<(&GroundedOn, &mut LastCheckpoint), With<Player>>,
+    checkpoint_query: Query<(), With<Checkpoint>>,
+) {
+    for (grounded_on, mut last_checkpoint) in &mut query {
+        if let Some(ground) = **grounded_on {
+            if checkpoint_query.contains(ground) {
+                **last_checkpoint = Some(ground);
+            }
+        }
     }
+}
 
-    if direction.y < 0. {
-        player_state.transition(PlayerEvent::Pull);
-    } else if direction.y > 0. {
-        if player_state.can(PlayerEvent::Jump) && player_state.transition(PlayerEvent::Jump) {
-            player_velocity.y = player.jump_velocity;
+/// Kill plane, in case the player gets under the track ground.
+pub fn fall_off_train(
+    mut query: Query<(&Transform, &mut Health), With<Player>>,
+    wagon_query: Query<Entity, With<Wagon>>,
+    spatial_hash: Res<SpatialHash>,
+) {
+    let bottom = wagon_query
+        .iter()
+        .filter_map(|wagon| spatial_hash.aabb(wagon))
+        .map(|aabb| aabb.min.y)
+        .fold(f32::INFINITY, f32::min);
+
+    for (transform, mut health) in &mut query {
+        if transform.translation.y < bottom - FALL_OFF_DISTANCE {
+            health.current = 0.;
         }
     }
+}
 
-    if direction.length().is_zero() && player_state.can(PlayerEvent::Stop) {
-        player_state.transition(PlayerEvent::Stop);
-        player_velocity.x = 0.;
+pub fn kill_player(mut query: Query<(&Health, &mut PlayerMachine, &mut Velocity), With<Player>>) {
+    for (health, mut player_state, mut velocity) in &mut query {
+        if health.is_dead() && !player_state.is(PlayerState::Dead) {
+            player_state.send(PlayerEvent::Die);
+            velocity.x = 0.;
+        }
     }
 }
 
-pub fn player_state_trigger_timer(
-    mut query: Query<(&mut PlayerState, &mut StateTriggerTimer)>,
-    time: Res<Time>,
+/// Moves the player back to its last checkpoint once the `Dead` state is over.
+/// Run with `exited(PlayerState::Dead)`.
+pub fn respawn_player(
+    mut entered_events: EventReader<StateEntered<PlayerState>>,
+    mut query: Query<
+        (
+            &mut Transform,
+            &mut Velocity,
+            &mut GroundedOn,
+            &mut Health,
+            &LastCheckpoint,
+        ),
+        With<Player>,
+    >,
+    checkpoint_query: Query<Entity, With<Checkpoint>>,
+    wagon_query: Query<Entity, With<Wagon>>,
+    spatial_hash: Res<SpatialHash>,
+    solid_query: Query<(), (Without<Dynamic>, Without<Sensor>)>,
+    velocity_query: Query<&Velocity, Without<Player>>,
+    parent_query: Query<&Parent>,
 ) {
-    let (mut player_state, mut stt) = query.single_mut();
-    // destructuring the tuple
-    let timer = stt.0.as_mut();
-
-    match (*player_state, timer) {
-        (PlayerState::Idle, None) => {
-            stt.0 = Some(Timer::from_seconds(2.5, TimerMode::Once));
+    for event in entered_events.read() {
+        if event.from != PlayerState::Dead {
+            continue;
         }
-        (PlayerState::Idle, Some(t)) => {
-            if t.tick(time.delta()).just_finished() {
-                player_state.transition(PlayerEvent::Push);
-                stt.0 = None;
-            }
+
+        let Ok((mut transform, mut velocity, mut grounded_on, mut health, last_checkpoint)) =
+            query.get_mut(event.entity)
+        else {
+            continue;
+        };
+        let respawn_wagon = respawn_wagon(
+            **last_checkpoint,
+            transform.translation.x,
+            &spatial_hash,
+            &checkpoint_query,
+            &wagon_query,
+        );
+        move_to_checkpoint(
+            drop_in_point(respawn_wagon, &spatial_hash, &solid_query),
+            &mut transform,
+            &mut velocity,
+            &mut grounded_on,
+            &velocity_query,
+            &parent_query,
+        );
+        health.restore();
+    }
+}
+
+/// Falling too far behind the train costs a life and brings the player back to
+/// the last checkpoint. Nothing happens while invulnerable, so a respawn that lands
+/// behind the train too doesn't drain the remaining lives at once.
+pub fn left_behind(
+    mut query: Query<(
+        &Player,
+        &mut Transform,
+        &mut Velocity,
+        &mut GroundedOn,
+        &mut Health,
+        &LastCheckpoint,
+    )>,
+    checkpoint_query: Query<Entity, With<Checkpoint>>,
+    wagon_query: Query<Entity, With<Wagon>>,
+    spatial_hash: Res<SpatialHash>,
+    solid_query: Query<(), (Without<Dynamic>, Without<Sensor>)>,
+    velocity_query: Query<&Velocity, Without<Player>>,
+    parent_query: Query<&Parent>,
+) {
+    let rear = wagon_query
+        .iter()
+        .filter_map(|wagon| spatial_hash.aabb(wagon))
+        .map(|aabb| aabb.min.x)
+        .fold(f32::INFINITY, f32::min);
+
+    for (player, mut transform, mut velocity, mut grounded_on, mut health, last_checkpoint) in
+        &mut query
+    {
+        if health.is_dead()
+            || health.is_invulnerable()
+            || transform.translation.x > rear - player.left_behind_distance
+        {
+            continue;
         }
-        (_, Some(_)) => {
-            stt.0 = None;
+
+        health.current = (health.current - 1.).max(0.);
+        if health.is_dead() {
+            // `kill_player` takes it from here
+            continue;
         }
-        _ => {}
+        health.invulnerable_for = health.invulnerability;
+        let respawn_wagon = respawn_wagon(
+            **last_checkpoint,
+            transform.translation.x,
+            &spatial_hash,
+            &checkpoint_query,
+            &wagon_query,
+        );
+        move_to_checkpoint(
+            drop_in_point(respawn_wagon, &spatial_hash, &solid_query),
+            &mut transform,
+            &mut velocity,
+            &mut grounded_on,
+            &velocity_query,
+            &parent_query,
+        );
     }
 }
 
-pub fn push_player(mut query: Query<(&PlayerState, &Player, &mut Velocity)>, time: Res<Time>) {
-    let (player_state, player, mut player_velocity) = query.single_mut();
-    if player_state.is(PlayerState::Pushing) {
-        /* player_velocity.x = move_towards(
-            player_velocity.x,
-            -player.max_speed * 2.,
-            2.,
-            time.delta_seconds(),
-        );
-        */
+/// Wagon to bring the player back to: the last checkpoint if it's still there, else
+/// the nearest checkpoint, else the rear wagon. The last checkpoint is gone once the
+/// endless train streams it out or the composition is reloaded.
+fn respawn_wagon(
+    last_checkpoint: Option<Entity>,
+    player_x: f32,
+    spatial_hash: &SpatialHash,
+    checkpoint_query: &Query<Entity, With<Checkpoint>>,
+    wagon_query: &Query<Entity, With<Wagon>>,
+) -> Option<Entity> {
+    if let Some(checkpoint) = last_checkpoint.filter(|&entity| spatial_hash.aabb(entity).is_some())
+    {
+        return Some(checkpoint);
+    }
+
+    // ties are broken by entity so the pick doesn't depend on the query order
+    let nearest_checkpoint = checkpoint_query
+        .iter()
+        .filter_map(|entity| {
+            Some((
+                (spatial_hash.aabb(entity)?.center().x - player_x).abs(),
+                entity,
+            ))
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    let rear_wagon = || {
+        wagon_query
+            .iter()
+            .filter_map(|entity| Some((spatial_hash.aabb(entity)?.min.x, entity)))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+    };
+    nearest_checkpoint
+        .or_else(rear_wagon)
+        .map(|(_, entity)| entity)
+}
+
+/// Where to drop the player in above `checkpoint`, on top of whatever stands in the
+/// middle of it (e.g. cargo) rather than inside it, and the collider under that point.
+fn drop_in_point(
+    checkpoint: Option<Entity>,
+    spatial_hash: &SpatialHash,
+    solid_query: &Query<(), (Without<Dynamic>, Without<Sensor>)>,
+) -> Option<(Vec2, Entity)> {
+    let checkpoint = checkpoint?;
+    let aabb = spatial_hash.aabb(checkpoint)?;
+    let origin = Vec2::new(aabb.center().x, aabb.max.y + DROP_IN_CLEARANCE);
+    let (top, ground) = spatial_hash
+        .raycast(
+            origin,
+            Vec2::NEG_Y,
+            DROP_IN_CLEARANCE + aabb.height(),
+            |entity| solid_query.contains(entity),
+        )
+        .map_or((aabb.max.y, checkpoint), |hit| (hit.point.y, hit.entity));
+    Some((Vec2::new(origin.x, top + DROP_IN_HEIGHT), ground))
+}
+
+fn move_to_checkpoint(
+    drop_in: Option<(Vec2, Entity)>,
+    transform: &mut Transform,
+    velocity: &mut Velocity,
+    grounded_on: &mut GroundedOn,
+    velocity_query: &Query<&Velocity, Without<Player>>,
+    parent_query: &Query<&Parent>,
+) {
+    if let Some((position, ground)) = drop_in {
+        transform.translation.x = position.x;
+        transform.translation.y = position.y;
+        // drop in moving along with the train
+        **velocity = platform_velocity(ground, velocity_query, parent_query);
+    } else {
+        **velocity = Vec2::ZERO;
+    }
+    **grounded_on = None;
+}
+
+/// Hides the player while dead, and makes it blink while invulnerable.
+pub fn flash_player(mut query: Query<(&Health, &PlayerMachine, &mut Visibility), With<Player>>) {
+    for (health, player_state, mut visibility) in &mut query {
+        let blink = health.is_invulnerable() && (health.invulnerable_for * 10.) as u32 % 2 == 1;
+        *visibility = if player_state.is(PlayerState::Dead) || blink {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+    }
+}
+
+pub fn push_player(
+    mut query: Query<(&Player, &mut PlayerMachine, &mut Stamina, &Children)>,
+    animation_query: Query<(&AnimationIndices, &AnimationTimer, &TextureAtlasSprite)>,
+    mut train_force: ResMut<TrainForce>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+
+    for (player, mut player_state, mut stamina, children) in &mut query {
+        let impulse = match player_state.state() {
+            PlayerState::Pushing => player.push_impulse,
+            PlayerState::Pulling => -player.pull_impulse,
+            _ => {
+                stamina.current = (stamina.current + stamina.recovery * delta).min(stamina.max);
+                if player_state.flag("exhausted")
+                    && stamina.current >= stamina.max * stamina.rested_ratio
+                {
+                    player_state.set_flag("exhausted", false);
+                }
+                continue;
+            }
+        };
+
+        stamina.current = (stamina.current - stamina.drain * delta).max(0.);
+        if stamina.current.is_zero() {
+            player_state.set_flag("exhausted", true);
+            player_state.send(PlayerEvent::Rest);
+            continue;
+        }
+
+        // only push when the animation reaches the frame where the hands hit the wagon
+        for (indices, animation_timer, sprite) in animation_query.iter_many(children.iter()) {
+            if animation_timer.just_finished() && indices.get_impulse_frame() == Some(sprite.index)
+            {
+                // `drive_train` integrates the force over the tick
+                train_force.force += impulse / delta;
+            }
+        }
     }
 }
 
@@ -239,31 +726,66 @@ pub fn animate_sprite(
 }
 
 pub fn animate_change(
-    state_query: Query<&PlayerState, Changed<PlayerState>>,
+    mut entered_events: EventReader<StateEntered<PlayerState>>,
     mut query: Query<(
         &mut AnimationIndices,
         &mut AnimationTimer,
         &mut AnimationCoolDownTimer,
         &mut TextureAtlasSprite,
     )>,
+    children_query: Query<&Children>,
 ) {
-    for state in &state_query {
-        let (mut indices, mut timer, mut cool_down_timer, mut sprite) = query.single_mut();
-
-        if cool_down_timer.compare_state(state) {
-            cool_down_timer.0 = None;
+    for event in entered_events.read() {
+        let state = &event.state;
+        let Ok(children) = children_query.get(event.entity) else {
             continue;
+        };
+        let mut sprites = query.iter_many_mut(children.iter());
+        while let Some((mut indices, mut timer, mut cool_down_timer, mut sprite)) =
+            sprites.fetch_next()
+        {
+            if cool_down_timer.compare_state(state) {
+                cool_down_timer.0 = None;
+                continue;
+            }
+
+            if let Some(cool_down) = indices.get_cool_down() {
+                *cool_down_timer = AnimationCoolDownTimer::new(
+                    Timer::from_seconds(cool_down, TimerMode::Once),
+                    *state,
+                );
+                continue;
+            }
+
+            update_animation(state, &mut indices, &mut timer, &mut sprite);
         }
+    }
+}
 
-        if let Some(cool_down) = indices.get_cool_down() {
-            *cool_down_timer = AnimationCoolDownTimer::new(
-                Timer::from_seconds(cool_down, TimerMode::Once),
-                state.clone(),
-            );
+pub fn squash_on_landing(
+    mut entered_events: EventReader<StateEntered<PlayerState>>,
+    mut query: Query<&mut Squash>,
+    children_query: Query<&Children>,
+) {
+    for event in entered_events.read() {
+        let Ok(children) = children_query.get(event.entity) else {
             continue;
+        };
+        let mut squashes = query.iter_many_mut(children.iter());
+        while let Some(mut squash) = squashes.fetch_next() {
+            match event.state {
+                PlayerState::Landing => squash.start(Vec2::new(0.2, -0.2), 0.15),
+                PlayerState::Stunned => squash.start(Vec2::new(0.4, -0.4), 0.4),
+                _ => {}
+            }
         }
+    }
+}
 
-        update_animation(state, &mut indices, &mut timer, &mut sprite);
+pub fn animate_squash(time: Res<Time>, mut query: Query<(&mut Squash, &mut Transform)>) {
+    for (mut squash, mut transform) in &mut query {
+        squash.tick(time.delta());
+        transform.scale = squash.scale().extend(1.);
     }
 }
 
@@ -274,15 +796,19 @@ pub fn animate_cool_down(
         &mut AnimationIndices,
         &mut AnimationTimer,
         &mut TextureAtlasSprite,
+        &Parent,
     )>,
-    state_query: Query<&PlayerState>,
+    state_query: Query<&PlayerMachine>,
 ) {
-    for (mut cool_down_timer, mut indices, mut animation_timer, mut sprite) in &mut query {
+    for (mut cool_down_timer, mut indices, mut animation_timer, mut sprite, parent) in &mut query {
         if let Some((timer, _)) = &mut cool_down_timer.0 {
             if timer.tick(time.delta()).just_finished() {
                 cool_down_timer.0 = None;
-                let state = state_query.single();
-                update_animation(state, &mut indices, &mut animation_timer, &mut sprite);
+                let Ok(player_state) = state_query.get(parent.get()) else {
+                    continue;
+                };
+                let state = player_state.state();
+                update_animation(&state, &mut indices, &mut animation_timer, &mut sprite);
             }
         }
     }
@@ -1,24 +1,42 @@
+use std::sync::Arc;
+
 use bevy::prelude::*;
 
+use crate::state_machine::{StateMachine, StateTable};
+
 use super::animation::*;
 
-#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub type PlayerMachine = StateMachine<PlayerState, PlayerEvent>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PlayerState {
     Idle,
     Moving,
     Jumping,
+    Falling,
+    /// short recovery after touching the ground
+    Landing,
+    /// longer recovery after a hard landing, the player can't move
+    Stunned,
+    Dead,
     Pulling,
     Pushing,
-    Invalid,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PlayerEvent {
     Move,
     Jump,
     Pull,
     Push,
     Stop,
+    Rest,
+    Fall,
+    Land,
+    HardLand,
+    Recover,
+    Die,
+    Respawn,
 }
 
 impl Default for PlayerState {
@@ -28,43 +46,66 @@ impl Default for PlayerState {
 }
 
 impl PlayerState {
-    pub fn is(&self, state: PlayerState) -> bool {
-        *self == state
-    }
-    fn transitions(&self, event: &PlayerEvent) -> PlayerState {
-        match (&self, event) {
-            (PlayerState::Idle, PlayerEvent::Move) => PlayerState::Moving,
-            (PlayerState::Idle, PlayerEvent::Push) => PlayerState::Pushing,
-            (PlayerState::Idle, PlayerEvent::Pull) => PlayerState::Pulling,
-            (PlayerState::Idle, PlayerEvent::Jump) => PlayerState::Jumping,
-            (PlayerState::Pushing, PlayerEvent::Move) => PlayerState::Moving,
-            (PlayerState::Pushing, PlayerEvent::Pull) => PlayerState::Pulling, // TODO: slow down
-            (PlayerState::Moving, PlayerEvent::Move) => PlayerState::Moving,
-            (PlayerState::Moving, PlayerEvent::Push) => PlayerState::Pushing,
-            (PlayerState::Moving, PlayerEvent::Stop) => PlayerState::Idle,
-            (PlayerState::Moving, PlayerEvent::Jump) => PlayerState::Jumping,
-            _ => PlayerState::Invalid,
-        }
-    }
-    pub fn can(&self, event: PlayerEvent) -> bool {
-        self.transitions(&event) != PlayerState::Invalid
-    }
-    pub fn transition(&mut self, event: PlayerEvent) -> bool {
-        let new_state = self.transitions(&event);
-        let change = new_state != PlayerState::Invalid;
-
-        if !change {
-            print!("Invalid transition from {:?} with {:?}\n", self, event)
-        } else {
-            print!("Transition from {:?} to {:?}\n", self, new_state)
-        }
+    pub fn machine() -> PlayerMachine {
+        use PlayerEvent::*;
+        use PlayerState::*;
 
-        *self = new_state;
+        let table = StateTable::new()
+            .on(Idle, Move, Moving)
+            .on_if(Idle, Push, Pushing, rested)
+            .on_if(Idle, Pull, Pulling, rested)
+            .on_if(Idle, Jump, Jumping, grounded)
+            .on(Idle, Fall, Falling)
+            .on(Pushing, Move, Moving)
+            .on(Pushing, Pull, Pulling)
+            .on(Pushing, Rest, Idle)
+            .on_if(Pushing, Jump, Jumping, grounded)
+            .on(Pushing, Fall, Falling)
+            .on(Pulling, Move, Moving)
+            .on(Pulling, Stop, Idle)
+            .on(Pulling, Rest, Idle)
+            .on_if(Pulling, Jump, Jumping, grounded)
+            .on(Pulling, Fall, Falling)
+            .on(Moving, Move, Moving)
+            .on_if(Moving, Push, Pushing, rested)
+            .on(Moving, Stop, Idle)
+            .on_if(Moving, Jump, Jumping, grounded)
+            .on(Moving, Fall, Falling)
+            .on(Jumping, Fall, Falling)
+            .on(Jumping, Land, Landing)
+            // still possible during the coyote time
+            .on_if(Falling, Jump, Jumping, grounded)
+            .on(Falling, Land, Landing)
+            .on(Falling, HardLand, Stunned)
+            .on(Landing, Move, Moving)
+            .on_if(Landing, Jump, Jumping, grounded)
+            .on(Landing, Fall, Falling)
+            .on(Landing, Recover, Idle)
+            // releasing the keys on the ground stops the momentum kept from the jump
+            .on_if(Landing, Stop, Landing, grounded)
+            .on_if(Idle, Stop, Idle, grounded)
+            .on(Stunned, Recover, Idle)
+            .after(Landing, 0.1, Recover)
+            .after(Stunned, 0.6, Recover)
+            .always(Die, Dead)
+            .on(Dead, Respawn, Idle)
+            .after(Dead, 2., Respawn)
+            // start pushing after standing still for a while
+            .after(Idle, 2.5, Push);
 
-        change
+        StateMachine::new(PlayerState::default(), Arc::new(table))
     }
 }
 
+fn rested(machine: &PlayerMachine) -> bool {
+    !machine.flag("exhausted")
+}
+
+/// On the ground, or walked off it less than `Player::coyote_time` ago.
+fn grounded(machine: &PlayerMachine) -> bool {
+    machine.flag("grounded")
+}
+
 impl AnimationState for PlayerState {
     fn get_animation(&self) -> AnimationIndices {
         match self {
@@ -77,6 +118,16 @@ impl AnimationState for PlayerState {
                 last: 12,
                 repeat_from: Some(11),
                 rate: 0.2,
+                impulse_frame: Some(12),
+                ..default()
+            }),
+            // TODO: use its own frames once the sprite sheet has them
+            PlayerState::Pulling => AnimationIndices::Straight(AnimationStraight {
+                first: 8,
+                last: 12,
+                repeat_from: Some(11),
+                rate: 0.25,
+                impulse_frame: Some(12),
                 ..default()
             }),
             PlayerState::Moving => AnimationIndices::Straight(AnimationStraight {
@@ -1,138 +1,376 @@
 use bevy::prelude::*;
 
-use crate::collision::Collider;
-
-#[derive(Clone, Copy, PartialEq)]
-enum WagonsType {
-    Head,
-    Small,
-    Medium,
-    Large,
+use crate::{
+    collision::{
+        platform_velocity, Collider, CollisionGroups, CollisionLayers, GroundedOn, OneWay,
+    },
+    input::Rumble,
+    simulation::{arg_value, SimulationRng},
+};
+
+use super::{
+    move_towards,
+    wagon::{TrainComposition, WagonCatalog, WagonDefinition, DEFAULT_TRAIN_PATH},
+    Hazard, Interpolated, LastCheckpoint, Player, TrainForce, Velocity,
+};
+
+#[derive(Component, Reflect)]
+pub struct Train {
+    /// 0 to 1, how much of the engine force is applied
+    pub throttle: f32,
+    /// 0 to 1, how much of the brake force is applied
+    pub brake: f32,
+    pub engine_force: f32,
+    pub brake_force: f32,
+    pub drag: f32,
+    pub mass: f32,
+    pub max_speed: f32,
 }
 
-mod wagon {
-    use super::WagonsType;
-
-    type AssetInfo<'a> = (&'a str, f32, f32);
-
-    pub const HEAD: AssetInfo = ("textures/train/wagon/head.png", 120., 63.);
-    pub const SMALL: AssetInfo = ("textures/train/wagon/small.png", 73., 63.);
-    pub const MEDIUM: AssetInfo = ("textures/train/wagon/medium.png", 122., 63.);
-    pub const LARGE: AssetInfo = ("textures/train/wagon/large.png", 287., 63.);
-    pub const WHEEL: AssetInfo = ("textures/train/wheel.png", 16., 16.);
-    pub const UNION: AssetInfo = ("textures/train/union.png", 32., 8.);
-    pub const TRACK: AssetInfo = ("textures/train/track.png", 16., 8.);
-
-    pub fn get_asset_info(wagon_type: WagonsType) -> AssetInfo<'static> {
-        match wagon_type {
-            WagonsType::Head => HEAD,
-            WagonsType::Small => SMALL,
-            WagonsType::Medium => MEDIUM,
-            WagonsType::Large => LARGE,
+impl Default for Train {
+    fn default() -> Self {
+        Self {
+            throttle: 0.5,
+            brake: 0.,
+            engine_force: 32.,
+            brake_force: 128.,
+            drag: 0.00078,
+            mass: 1.,
+            max_speed: 192.,
         }
     }
+}
 
-    pub fn get_wheel_position(
-        wagon_type: WagonsType,
-        wagon_width: f32,
-        wagon_height: f32,
-    ) -> Vec<(f32, f32)> {
-        let wheel_width = WHEEL.1;
-        let wheel_y = -wagon_height / 2. + 2.;
-        match wagon_type {
-            WagonsType::Head => vec![
-                (-wagon_width / 2. + 20., wheel_y),
-                (-wagon_width / 2. + wheel_width + 24., wheel_y),
-                (wagon_width / 2. - 18., wheel_y),
-            ],
-            WagonsType::Medium => vec![
-                (wagon_width / 2. - 22. - wheel_width, wheel_y),
-                (wagon_width / 2. - 18., wheel_y),
-            ],
-            WagonsType::Small => vec![(wagon_width / 2. - 18., wheel_y)],
-            WagonsType::Large => vec![
-                (-wagon_width / 2. + 20., wheel_y),
-                (-wagon_width / 2. + wheel_width + 24., wheel_y),
-                (-10., wheel_y),
-                (10., wheel_y),
-                (wagon_width / 2. - 22. - wheel_width, wheel_y),
-                (wagon_width / 2. - 18., wheel_y),
-            ],
-            _ => vec![],
-        }
+#[derive(Component)]
+pub struct Wagon;
+
+/// Wagon where the player respawns after dying, once they stood on it.
+#[derive(Component)]
+pub struct Checkpoint;
+
+/// Ground along the track, under the wheels, that follows the player so it never ends.
+#[derive(Component)]
+pub struct TrackGround;
+
+const TRACK_GROUND_SIZE: Vec2 = Vec2::new(2048., 32.);
+
+#[derive(Component)]
+pub struct Wheel {
+    pub radius: f32,
+}
+
+/// Asset path of the train composition, e.g. `--train train/endless.train.ron`.
+/// Replays use the one they were recorded with.
+#[derive(Resource, Clone, Deref)]
+pub struct TrainPath(pub String);
+
+impl TrainPath {
+    pub fn from_args() -> Self {
+        Self(arg_value("--train").unwrap_or_else(|| DEFAULT_TRAIN_PATH.to_string()))
     }
 }
 
-const WAGONS: [WagonsType; 6] = [
-    WagonsType::Head,
-    WagonsType::Medium,
-    WagonsType::Small,
-    WagonsType::Medium,
-    WagonsType::Large,
-    WagonsType::Medium,
-];
+/// Handle of the train composition, kept so it stays loaded and hot-reloads.
+#[derive(Resource)]
+pub struct TrainAssets {
+    pub composition: Handle<TrainComposition>,
+}
+
+/// Bottom of the wagons. The world layout doesn't depend on the window so replays
+/// run the same at any size, or headless.
+const WAGONS_Y: f32 = -96.;
+
+/// The wheels stick out under the wagons, down to the track.
+const WHEELS_BELOW_WAGONS: f32 = 6.;
 
 pub fn setup_train(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    windows: Query<&Window>,
+    train_path: Res<TrainPath>,
 ) {
-    let mut x = -200.;
+    commands.insert_resource(TrainAssets {
+        composition: asset_server.load(train_path.0.clone()),
+    });
 
-    let wheel_handle = asset_server.load(wagon::WHEEL.0);
+    // the wagons are spawned by `spawn_wagons` once the composition loads
+    commands.spawn((
+        SpatialBundle::from_transform(Transform::from_xyz(0., WAGONS_Y, 0.)),
+        Train::default(),
+        Velocity::default(),
+        Interpolated::default(),
+    ));
 
-    let windows_resolution = &windows.single().resolution;
+    let track_y = WAGONS_Y - WHEELS_BELOW_WAGONS;
+    commands.spawn((
+        SpatialBundle::from_transform(Transform::from_xyz(
+            0.,
+            track_y - TRACK_GROUND_SIZE.y / 2.,
+            0.,
+        )),
+        TrackGround,
+        Collider::Quad(TRACK_GROUND_SIZE),
+        CollisionGroups::new(CollisionLayers::OBSTACLE, CollisionLayers::ALL),
+    ));
+}
 
-    let train_entity = commands.spawn(SpatialBundle::default()).id();
+/// (Re)spawns the wagons of the train when its composition or wagon catalog is
+/// loaded or modified, keeping the train where it is. Players standing on the old
+/// wagons keep moving with the train until they land on the new ones.
+pub fn spawn_wagons(
+    mut commands: Commands,
+    mut composition_events: EventReader<AssetEvent<TrainComposition>>,
+    mut catalog_events: EventReader<AssetEvent<WagonCatalog>>,
+    train_assets: Res<TrainAssets>,
+    compositions: Res<Assets<TrainComposition>>,
+    catalogs: Res<Assets<WagonCatalog>>,
+    asset_server: Res<AssetServer>,
+    trains: Query<Entity, With<Train>>,
+    wagons: Query<Entity, With<Wagon>>,
+    mut players: Query<(&mut Velocity, &mut GroundedOn, &mut LastCheckpoint), With<Player>>,
+    velocity_query: Query<&Velocity, Without<Player>>,
+    parent_query: Query<&Parent>,
+) {
+    let composition_id = train_assets.composition.id();
+    let composition_changed = composition_events
+        .read()
+        .filter(|event| {
+            event.is_loaded_with_dependencies(composition_id) || event.is_modified(composition_id)
+        })
+        .count()
+        > 0;
+    let catalog_changed = catalog_events
+        .read()
+        .filter(|event| matches!(event, AssetEvent::Modified { .. }))
+        .count()
+        > 0;
+    if !composition_changed && !catalog_changed {
+        return;
+    }
 
-    for wagon in WAGONS.iter() {
-        let (texture, width, height) = wagon::get_asset_info(*wagon);
-        let origin_x = width / 2.;
-        let wagon_entity = commands
-            .spawn((
-                SpatialBundle {
-                    transform: Transform::from_xyz(
-                        x + origin_x,
-                        (-windows_resolution.height() * 0.3 + height) / 2. + 12., // TODO: 0.3 is from camera projection scale
-                        0.,
-                    ),
-                    ..default()
-                },
-                Collider::Quad(Vec2::new(width, height)),
-            ))
-            .with_children(|parent| {
-                // wagon
+    let Some(composition) = compositions.get(composition_id) else {
+        return;
+    };
+    let Some(catalog) = catalogs.get(&composition.catalog) else {
+        return;
+    };
+
+    let Ok(train) = trains.get_single() else {
+        return;
+    };
+    for wagon in &wagons {
+        commands.entity(wagon).despawn_recursive();
+    }
+    // the velocity of grounded bodies is relative to their ground, which is going away
+    for (mut velocity, mut grounded_on, mut last_checkpoint) in &mut players {
+        if let Some(ground) = grounded_on.filter(|&ground| wagons.contains(ground)) {
+            **velocity += platform_velocity(ground, &velocity_query, &parent_query);
+            **grounded_on = None;
+        }
+        **last_checkpoint = None;
+    }
+
+    let mut x = composition.start;
+    for name in &composition.wagons {
+        let Some(definition) = catalog.wagons.get(name) else {
+            warn!("unknown wagon type: {}", name);
+            continue;
+        };
+        let position = Vec2::new(x + definition.size.x / 2., definition.size.y / 2.);
+        let wagon = spawn_wagon(&mut commands, &asset_server, catalog, definition, position);
+        commands.entity(train).add_child(wagon);
+
+        x += definition.size.x + catalog.spacing;
+    }
+}
+
+/// Endless train: adds random wagons in front of the player and removes the ones
+/// far behind, for compositions with `EndlessSettings`.
+pub fn stream_wagons(
+    mut commands: Commands,
+    train_assets: Res<TrainAssets>,
+    compositions: Res<Assets<TrainComposition>>,
+    catalogs: Res<Assets<WagonCatalog>>,
+    asset_server: Res<AssetServer>,
+    mut rng: ResMut<SimulationRng>,
+    trains: Query<(Entity, &Transform), With<Train>>,
+    wagons: Query<(Entity, &Transform, &Collider), With<Wagon>>,
+    players: Query<&Transform, With<Player>>,
+) {
+    let Some(composition) = compositions.get(&train_assets.composition) else {
+        return;
+    };
+    let Some(endless) = composition.endless else {
+        return;
+    };
+    let Some(catalog) = catalogs.get(&composition.catalog) else {
+        return;
+    };
+
+    let Ok((train, train_transform)) = trains.get_single() else {
+        return;
+    };
+    // wagons are positioned relative to the train, and streamed around every player
+    let (rearmost, frontmost) = players
+        .iter()
+        .map(|transform| transform.translation.x - train_transform.translation.x)
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), x| {
+            (min.min(x), max.max(x))
+        });
+    if rearmost > frontmost {
+        return;
+    }
+
+    let mut front = composition.start;
+    for (wagon, transform, collider) in &wagons {
+        let aabb = collider.aabb(transform);
+        if aabb.max.x < rearmost - endless.view_distance {
+            commands.entity(wagon).despawn_recursive();
+        }
+        front = front.max(aabb.max.x + catalog.spacing);
+    }
+
+    while front < frontmost + endless.view_distance {
+        let difficulty = endless.difficulty(front - composition.start);
+        let Some((_, definition)) = catalog.pick(difficulty, &mut rng) else {
+            return;
+        };
+        let position = Vec2::new(front + definition.size.x / 2., definition.size.y / 2.);
+        let wagon = spawn_wagon(&mut commands, &asset_server, catalog, definition, position);
+        commands.entity(train).add_child(wagon);
+
+        front += definition.size.x + catalog.spacing;
+    }
+}
+
+/// Spawns a wagon with its wheels and union, `position` is relative to the train.
+pub fn spawn_wagon(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    catalog: &WagonCatalog,
+    definition: &WagonDefinition,
+    position: Vec2,
+) -> Entity {
+    let mut wagon_commands = commands.spawn((
+        SpatialBundle::from_transform(Transform::from_translation(position.extend(0.))),
+        Wagon,
+        definition.collider(),
+        CollisionGroups::new(CollisionLayers::WAGON, CollisionLayers::ALL),
+    ));
+    if definition.checkpoint {
+        wagon_commands.insert(Checkpoint);
+    }
+    if definition.one_way {
+        wagon_commands.insert(OneWay);
+    }
+    if let Some(damage) = definition.hazard {
+        wagon_commands.insert(Hazard { damage });
+    }
+
+    wagon_commands
+        .with_children(|parent| {
+            // wagon
+            parent.spawn(SpriteBundle {
+                texture: asset_server.load(&definition.texture),
+                transform: Transform::from_xyz(0., 0., 1.),
+                ..default()
+            });
+
+            let wheel_texture: Handle<Image> = asset_server.load(&catalog.wheel_texture);
+            for wheel in &definition.wheels {
+                parent.spawn((
+                    SpriteBundle {
+                        texture: wheel_texture.clone(),
+                        transform: Transform::from_translation(wheel.extend(0.)),
+                        ..default()
+                    },
+                    Wheel {
+                        radius: catalog.wheel_radius,
+                    },
+                ));
+            }
+
+            if let Some(union) = definition.union {
                 parent.spawn(SpriteBundle {
-                    texture: asset_server.load(texture),
-                    transform: Transform::from_xyz(0., 0., 1.),
+                    texture: asset_server.load(&catalog.union_texture),
+                    transform: Transform::from_translation(union.extend(0.)),
                     ..default()
                 });
+            }
+        })
+        .id()
+}
 
-                for (x, y) in wagon::get_wheel_position(*wagon, width, height) {
-                    parent.spawn(SpriteBundle {
-                        texture: wheel_handle.clone(),
-                        transform: Transform::from_xyz(x, y, 0.),
-                        ..default()
-                    });
-                }
-
-                if *wagon != WagonsType::Head {
-                    let (union_texture, union_width, _) = wagon::UNION;
-                    parent.spawn(SpriteBundle {
-                        texture: asset_server.load(union_texture),
-                        transform: Transform::from_xyz(
-                            (-width - union_width) / 2.,
-                            -height / 2. + 4.,
-                            0.,
-                        ),
-                        ..default()
-                    });
-                }
-            })
-            .id();
+/// Run condition, the simulation waits for the wagons to be spawned.
+pub fn train_ready(wagons: Query<(), With<Wagon>>) -> bool {
+    !wagons.is_empty()
+}
+
+pub fn move_track_ground(
+    players: Query<&Transform, (With<Player>, Without<TrackGround>)>,
+    mut grounds: Query<&mut Transform, With<TrackGround>>,
+) {
+    let Ok(player_transform) = players.get_single() else {
+        return;
+    };
+    let player_x = player_transform.translation.x;
+    for mut transform in &mut grounds {
+        transform.translation.x = player_x;
+    }
+}
 
-        commands.entity(train_entity).push_children(&[wagon_entity]);
+pub fn drive_train(
+    mut train_force: ResMut<TrainForce>,
+    mut query: Query<(&Train, &mut Velocity)>,
+    time: Res<Time>,
+) {
+    let delta = time.delta_seconds();
+    for (train, mut velocity) in &mut query {
+        let speed = velocity.x;
+
+        let force = train.throttle * train.engine_force + train_force.force
+            - train.drag * speed * speed.abs();
+        let new_speed = speed + force / train.mass * delta;
+        // brakes slow the train down but never push it backwards
+        let new_speed = move_towards(
+            new_speed,
+            0.,
+            train.brake * train.brake_force / train.mass,
+            delta,
+        );
+
+        velocity.x = new_speed.clamp(-train.max_speed, train.max_speed);
+        train_force.acceleration = (velocity.x - speed) / delta;
+    }
 
-        x += width + wagon::UNION.1;
+    // the force is accumulated by other systems every tick
+    train_force.force = 0.;
+}
+
+/// Deceleration of the train, in units per second squared, felt on the gamepad.
+const HARD_BRAKING: f32 = 96.;
+
+pub fn rumble_on_braking(
+    train_force: Res<TrainForce>,
+    mut was_braking: Local<bool>,
+    mut rumble_events: EventWriter<Rumble>,
+) {
+    let braking = train_force.acceleration < -HARD_BRAKING;
+    if braking && !*was_braking {
+        rumble_events.send(Rumble::new(0.8, 0.4));
+    }
+    *was_braking = braking;
+}
+
+pub fn spin_wheels(
+    trains: Query<&Velocity, With<Train>>,
+    mut wheels: Query<(&mut Transform, &Wheel)>,
+    time: Res<Time>,
+) {
+    let Ok(velocity) = trains.get_single() else {
+        return;
+    };
+    let distance = velocity.x * time.delta_seconds();
+    for (mut transform, wheel) in &mut wheels {
+        transform.rotate_z(-distance / wheel.radius);
     }
 }
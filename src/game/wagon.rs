@@ -0,0 +1,218 @@
+//! Wagon types and train compositions, loaded from RON assets so designers can
+//! change the train without recompiling. Both are hot-reloaded.
+//!
+//! - `*.wagons.ron`: a [`WagonCatalog`] with the texture, size, wheels and
+//!   properties of every wagon type.
+//! - `*.train.ron`: a [`TrainComposition`], the list of wagon types from the first
+//!   wagon to the last one, and the catalog they come from. Compositions with
+//!   [`EndlessSettings`] keep adding random wagons in front of the player.
+
+use std::fmt;
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::{BoxedFuture, HashMap},
+};
+use serde::Deserialize;
+
+use crate::{collision::Collider, simulation::SimulationRng};
+
+pub const DEFAULT_TRAIN_PATH: &str = "train/default.train.ron";
+
+#[derive(Deserialize, Clone)]
+pub struct WagonDefinition {
+    pub texture: String,
+    pub size: Vec2,
+    /// wheel positions relative to the wagon center
+    #[serde(default)]
+    pub wheels: Vec<Vec2>,
+    /// defaults to a quad of `size`
+    #[serde(default)]
+    pub collider: Option<Collider>,
+    /// position of the union with the previous wagon, if any
+    #[serde(default)]
+    pub union: Option<Vec2>,
+    /// the player respawns on the last checkpoint wagon they stood on
+    #[serde(default)]
+    pub checkpoint: bool,
+    /// the roof can be jumped through from below and dropped through from above
+    #[serde(default)]
+    pub one_way: bool,
+    /// damage dealt to whoever touches the wagon, e.g. an electrified one
+    #[serde(default)]
+    pub hazard: Option<f32>,
+    /// chance of being picked by the endless train relative to the other types,
+    /// at difficulty 0 and 1. Never picked by default.
+    #[serde(default)]
+    pub weights: (f32, f32),
+}
+
+impl WagonDefinition {
+    pub fn collider(&self) -> Collider {
+        self.collider.clone().unwrap_or(Collider::Quad(self.size))
+    }
+
+    pub fn weight(&self, difficulty: f32) -> f32 {
+        let (easy, hard) = self.weights;
+        (easy + (hard - easy) * difficulty).max(0.)
+    }
+}
+
+#[derive(Asset, TypePath, Deserialize)]
+pub struct WagonCatalog {
+    pub wheel_texture: String,
+    pub wheel_radius: f32,
+    pub union_texture: String,
+    /// distance between two wagons, covered by the union
+    pub spacing: f32,
+    pub wagons: HashMap<String, WagonDefinition>,
+}
+
+impl WagonCatalog {
+    /// Random wagon type, weighted for `difficulty`.
+    pub fn pick(
+        &self,
+        difficulty: f32,
+        rng: &mut SimulationRng,
+    ) -> Option<(&String, &WagonDefinition)> {
+        // sorted so the pick doesn't depend on the map order
+        let mut candidates: Vec<_> = self
+            .wagons
+            .iter()
+            .map(|(name, definition)| (name, definition, definition.weight(difficulty)))
+            .filter(|(_, _, weight)| *weight > 0.)
+            .collect();
+        candidates.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        let total: f32 = candidates.iter().map(|(_, _, weight)| weight).sum();
+        let mut roll = rng.range(0., total);
+        for (name, definition, weight) in &candidates {
+            if roll < *weight {
+                return Some((*name, *definition));
+            }
+            roll -= weight;
+        }
+        candidates
+            .last()
+            .map(|(name, definition, _)| (*name, *definition))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct EndlessSettings {
+    /// wagons are spawned this far in front of the player and despawned this far behind
+    pub view_distance: f32,
+    /// train length at which the difficulty reaches 1
+    pub difficulty_length: f32,
+    /// exponent of the difficulty curve, over 1 the difficulty ramps up later
+    pub difficulty_curve: f32,
+}
+
+impl EndlessSettings {
+    /// Difficulty from 0 to 1 at `length` from the start of the train.
+    pub fn difficulty(&self, length: f32) -> f32 {
+        (length / self.difficulty_length)
+            .clamp(0., 1.)
+            .powf(self.difficulty_curve)
+    }
+}
+
+#[derive(Asset, TypePath)]
+pub struct TrainComposition {
+    #[dependency]
+    pub catalog: Handle<WagonCatalog>,
+    /// x of the back of the first wagon
+    pub start: f32,
+    pub wagons: Vec<String>,
+    pub endless: Option<EndlessSettings>,
+}
+
+#[derive(Deserialize)]
+struct TrainCompositionFile {
+    catalog: String,
+    start: f32,
+    wagons: Vec<String>,
+    #[serde(default)]
+    endless: Option<EndlessSettings>,
+}
+
+#[derive(Debug)]
+pub enum RonAssetError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+}
+
+impl fmt::Display for RonAssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RonAssetError::Io(error) => write!(f, "couldn't read asset: {}", error),
+            RonAssetError::Ron(error) => write!(f, "invalid RON: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RonAssetError {}
+
+async fn read_ron<T: for<'de> Deserialize<'de>>(
+    reader: &mut Reader<'_>,
+) -> Result<T, RonAssetError> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(RonAssetError::Io)?;
+    ron::de::from_bytes(&bytes).map_err(RonAssetError::Ron)
+}
+
+#[derive(Default)]
+pub struct WagonCatalogLoader;
+
+impl AssetLoader for WagonCatalogLoader {
+    type Asset = WagonCatalog;
+    type Settings = ();
+    type Error = RonAssetError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<WagonCatalog, RonAssetError>> {
+        Box::pin(async move { read_ron(reader).await })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["wagons.ron"]
+    }
+}
+
+#[derive(Default)]
+pub struct TrainCompositionLoader;
+
+impl AssetLoader for TrainCompositionLoader {
+    type Asset = TrainComposition;
+    type Settings = ();
+    type Error = RonAssetError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<TrainComposition, RonAssetError>> {
+        Box::pin(async move {
+            let file: TrainCompositionFile = read_ron(reader).await?;
+            Ok(TrainComposition {
+                catalog: load_context.load(file.catalog),
+                start: file.start,
+                wagons: file.wagons,
+                endless: file.endless,
+            })
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["train.ron"]
+    }
+}
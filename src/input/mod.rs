@@ -0,0 +1,406 @@
+//! Input sampled once per fixed step, gameplay systems read the actions in
+//! `PlayerInput` instead of the devices so it can be recorded and replayed.
+//!
+//! Actions are resolved from the `InputBindings`, which are loaded from
+//! `input_bindings.cfg` and can be rebound at runtime through `Rebinding`.
+//! Horizontal movement can also come from the left stick, see `PlayerInput::direction`.
+
+use std::fs;
+
+use bevy::{
+    prelude::*,
+    reflect::{DynamicEnum, DynamicVariant},
+    utils::HashMap,
+};
+
+mod rumble;
+pub use rumble::*;
+
+const INPUT_BINDINGS_PATH: &str = "input_bindings.cfg";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Pull,
+    Push,
+    Pause,
+    Interact,
+}
+
+impl Action {
+    pub const ALL: [Action; 7] = [
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::Jump,
+        Action::Pull,
+        Action::Push,
+        Action::Pause,
+        Action::Interact,
+    ];
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Key(KeyCode),
+    Gamepad(GamepadButtonType),
+}
+
+impl Binding {
+    fn parse(text: &str) -> Option<Self> {
+        let (device, name) = text.trim().strip_suffix(')')?.split_once('(')?;
+        match device {
+            "Key" => variant_from_name(name).map(Binding::Key),
+            "Gamepad" => variant_from_name(name).map(Binding::Gamepad),
+            _ => None,
+        }
+    }
+
+    fn same_device(&self, other: &Binding) -> bool {
+        matches!(
+            (self, other),
+            (Binding::Key(_), Binding::Key(_)) | (Binding::Gamepad(_), Binding::Gamepad(_))
+        )
+    }
+}
+
+/// Builds a unit variant of `T` from its name, e.g. `KeyCode::Space` from "Space".
+fn variant_from_name<T: FromReflect>(name: &str) -> Option<T> {
+    T::from_reflect(&DynamicEnum::new(name, DynamicVariant::Unit))
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct InputBindings {
+    bindings: HashMap<Action, Vec<Binding>>,
+    /// stick values under this are ignored, the rest is rescaled to 0..1
+    pub stick_deadzone: f32,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        use Binding::*;
+
+        let bindings = [
+            (
+                Action::MoveLeft,
+                vec![
+                    Key(KeyCode::Left),
+                    Key(KeyCode::A),
+                    Gamepad(GamepadButtonType::DPadLeft),
+                ],
+            ),
+            (
+                Action::MoveRight,
+                vec![
+                    Key(KeyCode::Right),
+                    Key(KeyCode::D),
+                    Gamepad(GamepadButtonType::DPadRight),
+                ],
+            ),
+            (
+                Action::Jump,
+                vec![
+                    Key(KeyCode::Up),
+                    Key(KeyCode::Space),
+                    Gamepad(GamepadButtonType::South),
+                ],
+            ),
+            (
+                Action::Pull,
+                vec![
+                    Key(KeyCode::Down),
+                    Key(KeyCode::S),
+                    Gamepad(GamepadButtonType::West),
+                ],
+            ),
+            (
+                Action::Push,
+                vec![Key(KeyCode::X), Gamepad(GamepadButtonType::East)],
+            ),
+            (
+                Action::Pause,
+                vec![Key(KeyCode::Escape), Gamepad(GamepadButtonType::Start)],
+            ),
+            (
+                Action::Interact,
+                vec![Key(KeyCode::E), Gamepad(GamepadButtonType::North)],
+            ),
+        ];
+
+        Self {
+            bindings: bindings.into_iter().collect(),
+            stick_deadzone: 0.2,
+        }
+    }
+}
+
+impl InputBindings {
+    /// Bindings from the settings file, falling back to the defaults for missing actions.
+    pub fn load() -> Self {
+        let mut input_bindings = Self::default();
+        let Ok(text) = fs::read_to_string(INPUT_BINDINGS_PATH) else {
+            return input_bindings;
+        };
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, bindings)) = line.split_once('=') else {
+                println!("invalid input binding: {}", line);
+                continue;
+            };
+            if name.trim() == "StickDeadzone" {
+                match bindings.trim().parse() {
+                    Ok(deadzone) => input_bindings.stick_deadzone = deadzone,
+                    Err(_) => println!("invalid stick deadzone: {}", bindings),
+                }
+                continue;
+            }
+            let Some(action) = Action::ALL
+                .into_iter()
+                .find(|action| format!("{:?}", action) == name.trim())
+            else {
+                println!("unknown action: {}", name);
+                continue;
+            };
+
+            let bindings = bindings
+                .split(',')
+                .filter(|binding| !binding.trim().is_empty())
+                .filter_map(|binding| {
+                    let parsed = Binding::parse(binding);
+                    if parsed.is_none() {
+                        println!("unknown binding for {:?}: {}", action, binding);
+                    }
+                    parsed
+                })
+                .collect();
+            input_bindings.bindings.insert(action, bindings);
+        }
+
+        input_bindings
+    }
+
+    pub fn save(&self) {
+        let mut text = String::from("# action = Key(KeyCode), Gamepad(GamepadButtonType)\n");
+        for action in Action::ALL {
+            let bindings: Vec<String> = self
+                .get(action)
+                .iter()
+                .map(|binding| match binding {
+                    Binding::Key(key) => format!("Key({:?})", key),
+                    Binding::Gamepad(button) => format!("Gamepad({:?})", button),
+                })
+                .collect();
+            text += &format!("{:?} = {}\n", action, bindings.join(", "));
+        }
+        text += &format!("StickDeadzone = {}\n", self.stick_deadzone);
+
+        if let Err(error) = fs::write(INPUT_BINDINGS_PATH, text) {
+            eprintln!("couldn't save input bindings: {}", error);
+        }
+    }
+
+    pub fn get(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    /// Replaces the bindings of the same device for `action`, and unbinds it from
+    /// any other action.
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        for bindings in self.bindings.values_mut() {
+            bindings.retain(|other| *other != binding);
+        }
+        let bindings = self.bindings.entry(action).or_default();
+        bindings.retain(|other| !other.same_device(&binding));
+        bindings.push(binding);
+    }
+
+    fn stick_value(&self, value: f32) -> f32 {
+        if value.abs() < self.stick_deadzone {
+            return 0.;
+        }
+        let scaled = (value.abs() - self.stick_deadzone) / (1. - self.stick_deadzone);
+        scaled.min(1.) * value.signum()
+    }
+
+    fn pressed(
+        &self,
+        action: Action,
+        keyboard_input: &Input<KeyCode>,
+        gamepad_input: &Input<GamepadButton>,
+        gamepads: &Gamepads,
+    ) -> bool {
+        self.get(action).iter().any(|binding| match binding {
+            Binding::Key(key) => keyboard_input.pressed(*key),
+            Binding::Gamepad(button) => gamepads
+                .iter()
+                .any(|gamepad| gamepad_input.pressed(GamepadButton::new(gamepad, *button))),
+        })
+    }
+
+    fn just_pressed(
+        &self,
+        action: Action,
+        keyboard_input: &Input<KeyCode>,
+        gamepad_input: &Input<GamepadButton>,
+        gamepads: &Gamepads,
+    ) -> bool {
+        self.get(action).iter().any(|binding| match binding {
+            Binding::Key(key) => keyboard_input.just_pressed(*key),
+            Binding::Gamepad(button) => gamepads
+                .iter()
+                .any(|gamepad| gamepad_input.just_pressed(GamepadButton::new(gamepad, *button))),
+        })
+    }
+}
+
+/// Set to an action to bind it to the next key or gamepad button pressed
+/// (e.g. from the inspector).
+#[derive(Resource, Debug, Default, Reflect)]
+#[reflect(Resource)]
+pub struct Rebinding(pub Option<Action>);
+
+/// Actions held in the current fixed step.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq)]
+pub struct PlayerInput {
+    pressed: u8,
+    /// horizontal tilt of the left stick past the deadzone, from -1 to 1
+    move_axis: f32,
+}
+
+impl PlayerInput {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed & action.bit() != 0
+    }
+
+    pub fn set(&mut self, action: Action, pressed: bool) {
+        if pressed {
+            self.pressed |= action.bit();
+        } else {
+            self.pressed &= !action.bit();
+        }
+    }
+
+    /// Text form used by replays: the bits of the held actions and the stick tilt.
+    pub fn to_record(self) -> String {
+        format!("{} {}", self.pressed, self.move_axis)
+    }
+
+    pub fn from_record(record: &str) -> Option<Self> {
+        let (pressed, move_axis) = record.split_once(' ')?;
+        Some(Self {
+            pressed: pressed.parse().ok()?,
+            move_axis: move_axis.parse().ok()?,
+        })
+    }
+
+    /// Movement direction, the stick gives proportional speeds while the buttons
+    /// always move at full speed.
+    pub fn direction(&self) -> Vec2 {
+        let mut direction = Vec2::ZERO;
+        if self.pressed(Action::MoveLeft) {
+            direction.x -= 1.;
+        }
+        if self.pressed(Action::MoveRight) {
+            direction.x += 1.;
+        }
+        if direction.x == 0. {
+            direction.x = self.move_axis;
+        }
+        if self.pressed(Action::Jump) {
+            direction.y += 1.;
+        }
+        if self.pressed(Action::Pull) {
+            direction.y -= 1.;
+        }
+        direction
+    }
+}
+
+pub fn read_player_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    gamepads: Res<Gamepads>,
+    bindings: Res<InputBindings>,
+    mut input: ResMut<PlayerInput>,
+) {
+    for action in Action::ALL {
+        let pressed = bindings.pressed(action, &keyboard_input, &gamepad_input, &gamepads);
+        input.set(action, pressed);
+    }
+
+    // the most tilted stick wins when several gamepads are connected
+    input.move_axis = gamepads
+        .iter()
+        .filter_map(|gamepad| {
+            gamepad_axes.get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickX))
+        })
+        .map(|value| bindings.stick_value(value))
+        .fold(0., |axis: f32, value| {
+            if value.abs() > axis.abs() {
+                value
+            } else {
+                axis
+            }
+        });
+}
+
+pub fn not_rebinding(rebinding: Res<Rebinding>) -> bool {
+    rebinding.0.is_none()
+}
+
+/// Pausing stops the virtual clock, so `FixedUpdate` stops running too.
+pub fn toggle_pause(
+    keyboard_input: Res<Input<KeyCode>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+    gamepads: Res<Gamepads>,
+    bindings: Res<InputBindings>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    if !bindings.just_pressed(Action::Pause, &keyboard_input, &gamepad_input, &gamepads) {
+        return;
+    }
+
+    if time.is_paused() {
+        time.unpause();
+    } else {
+        time.pause();
+    }
+}
+
+pub fn rebind_action(
+    mut rebinding: ResMut<Rebinding>,
+    mut bindings: ResMut<InputBindings>,
+    keyboard_input: Res<Input<KeyCode>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+) {
+    let Some(action) = rebinding.0 else {
+        return;
+    };
+
+    let binding = keyboard_input
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            gamepad_input
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Gamepad(button.button_type))
+        });
+
+    if let Some(binding) = binding {
+        info!("{:?} bound to {:?}", action, binding);
+        bindings.rebind(action, binding);
+        bindings.save();
+        rebinding.0 = None;
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use bevy::{
+    input::gamepad::{GamepadRumbleIntensity, GamepadRumbleRequest},
+    prelude::*,
+};
+
+/// Shakes every connected gamepad, sent by gameplay systems (e.g. landing or a
+/// hard braking of the train).
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Rumble {
+    /// 0 to 1
+    pub intensity: f32,
+    pub seconds: f32,
+}
+
+impl Rumble {
+    pub fn new(intensity: f32, seconds: f32) -> Self {
+        Self { intensity, seconds }
+    }
+}
+
+pub fn play_rumble(
+    mut rumble_events: EventReader<Rumble>,
+    gamepads: Res<Gamepads>,
+    mut rumble_requests: EventWriter<GamepadRumbleRequest>,
+) {
+    for rumble in rumble_events.read() {
+        for gamepad in gamepads.iter() {
+            rumble_requests.send(GamepadRumbleRequest::Add {
+                gamepad,
+                duration: Duration::from_secs_f32(rumble.seconds),
+                intensity: GamepadRumbleIntensity {
+                    strong_motor: rumble.intensity,
+                    weak_motor: rumble.intensity,
+                },
+            });
+        }
+    }
+}
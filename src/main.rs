@@ -1,17 +1,35 @@
 #![allow(clippy::type_complexity)]
 
-use bevy::prelude::*;
+use std::time::Duration;
+
+use bevy::{app::ScheduleRunnerPlugin, input::InputPlugin, prelude::*, time::TimeUpdateStrategy};
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 
 mod menu;
 use menu::*;
 
 mod game;
-use game::{common::*, player::*, train::*};
+use game::{common::*, health::*, player::*, train::*, wagon::*};
 
 mod collision;
 use collision::*;
 
+mod state_machine;
+use state_machine::*;
+
+mod simulation;
+use simulation::*;
+
+mod input;
+use input::*;
+
+mod replay;
+use replay::*;
+
+/// Default rate of the `FixedUpdate` simulation, rendering is interpolated between
+/// steps. Override it with `--hz <rate>`.
+const FIXED_TIMESTEP_HZ: f64 = 64.;
+
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
 pub enum AppState {
     Menu,
@@ -20,45 +38,158 @@ pub enum AppState {
 }
 
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins.set(
+    let mut replay = Replay::from_args();
+    // replays run with the seed, rate and train they were recorded with
+    let simulation = replay
+        .seed()
+        .map_or_else(Simulation::from_args, |seed| Simulation { seed });
+    let train_path = replay
+        .train()
+        .map_or_else(TrainPath::from_args, |path| TrainPath(path.to_string()));
+    let fixed_timestep_hz = replay.hz().unwrap_or_else(|| {
+        arg_value("--hz")
+            .and_then(|hz| hz.parse().ok())
+            .filter(|hz: &f64| *hz > 0.)
+            .unwrap_or(FIXED_TIMESTEP_HZ)
+    });
+    replay.record_from_args(&simulation, fixed_timestep_hz, &train_path);
+
+    let mut app = App::new();
+    if has_arg("--headless") {
+        // no window or rendering, one fixed step per update as fast as possible
+        app.add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::ZERO)),
+            AssetPlugin::default(),
+            TransformPlugin,
+            HierarchyPlugin,
+            InputPlugin,
+        ))
+        .init_asset::<Image>()
+        .init_asset::<TextureAtlas>()
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1. / fixed_timestep_hz,
+        )));
+    } else {
+        app.add_plugins(DefaultPlugins.set(
             // This sets image filtering to nearest
             // This is done to prevent textures with low resolution (e.g. pixel art) from being blurred
             // by linear filtering.
             ImagePlugin::default_nearest(),
         ))
-        .add_plugins(WorldInspectorPlugin::new())
-        .add_state::<AppState>()
+        .add_plugins(WorldInspectorPlugin::new());
+    }
+
+    app.add_state::<AppState>()
+        .insert_resource(Time::<Fixed>::from_hz(fixed_timestep_hz))
+        .insert_resource(simulation)
+        .insert_resource(SimulationRng::new(simulation.seed))
+        .insert_resource(train_path)
+        .init_resource::<SimulationTick>()
+        .init_resource::<PlayerInput>()
+        .insert_resource(InputBindings::load())
+        .init_resource::<Rebinding>()
+        .insert_resource(replay)
+        .init_asset::<WagonCatalog>()
+        .init_asset::<TrainComposition>()
+        .init_asset_loader::<WagonCatalogLoader>()
+        .init_asset_loader::<TrainCompositionLoader>()
+        .add_event::<Rumble>()
+        .add_event::<Damage>()
         .add_event::<CollisionEvent>()
+        .add_event::<CollisionStarted>()
+        .add_event::<CollisionOngoing>()
+        .add_event::<CollisionEnded>()
+        .add_event::<SensorEntered>()
+        .add_event::<SensorExited>()
+        .add_event::<StateEntered<PlayerState>>()
+        .add_event::<StateExited<PlayerState>>()
         .insert_resource(Gravity::default())
         .insert_resource(TrainForce::default())
+        .init_resource::<SpatialHash>()
+        .init_resource::<Contacts>()
         .register_type::<Player>()
+        .register_type::<Stamina>()
+        .register_type::<Health>()
+        .register_type::<Train>()
+        .register_type::<CollisionGroups>()
+        .register_type::<Rebinding>()
         // 143, 222, 93 -> 0.56, 0.87, 0.36
         .insert_resource(ClearColor(Color::rgb(0.56, 0.87, 0.36)))
         .add_systems(Startup, setup)
         .add_systems(OnEnter(AppState::Menu), setup_menu)
         .add_systems(Update, menu_sys.run_if(in_state(AppState::Menu)))
         .add_systems(OnExit(AppState::Menu), cleanup_menu)
-        .add_systems(OnEnter(AppState::InGame), (setup_train, setup_player))
-        .add_systems(Update, camera_follow)
+        .add_systems(
+            OnEnter(AppState::InGame),
+            (reset_simulation, setup_train, setup_player).chain(),
+        )
+        .add_systems(Update, spawn_wagons.run_if(in_state(AppState::InGame)))
+        .add_systems(Update, (interpolate_translation, camera_follow).chain())
+        .add_systems(
+            Update,
+            (
+                rebind_action,
+                toggle_pause.run_if(not_rebinding),
+                play_rumble,
+            ),
+        )
+        .add_systems(Last, (remove_despawned_colliders, finish_recording))
         .add_systems(
             FixedUpdate,
             (
-                move_player,
+                (
+                    restore_simulated_translation,
+                    read_player_input,
+                    play_replay_input,
+                    record_input,
+                )
+                    .chain(),
+                (move_player, control_jump).chain(),
                 push_player,
-                player_state_trigger_timer,
+                (drive_train, rumble_on_braking, stream_wagons).chain(),
+                (
+                    update_state_machines::<PlayerState, PlayerEvent>,
+                    respawn_player.run_if(exited(PlayerState::Dead)),
+                    rumble_on_death.run_if(entered(PlayerState::Dead)),
+                )
+                    .chain(),
                 animate_sprite,
-                animate_change,
+                (animate_change, squash_on_landing, animate_squash).chain(),
                 apply_gravity,
+                (
+                    move_track_ground,
+                    remove_despawned_colliders,
+                    update_spatial_hash,
+                )
+                    .chain(),
                 check_for_collisions,
+                (track_contacts, rumble_on_landing, log_sensor_events).chain(),
+                (
+                    damage_from_hazards,
+                    apply_damage,
+                    track_checkpoint,
+                    fall_off_train,
+                    left_behind,
+                    kill_player,
+                    flash_player,
+                )
+                    .chain(),
                 animate_cool_down,
                 apply_velocity,
+                spin_wheels,
+                record_simulated_translation,
+                advance_simulation_tick,
             )
                 // `chain`ing systems toether runs them in order
                 .chain()
-                .run_if(in_state(AppState::InGame)),
+                .run_if(in_state(AppState::InGame))
+                .run_if(train_ready),
         )
         .run();
+
+    if app.world.resource::<Replay>().diverged() {
+        std::process::exit(1);
+    }
 }
 
 fn setup(mut commands: Commands) {
@@ -72,7 +203,9 @@ fn camera_follow(
     mut cameras: Query<&mut Transform, (With<Camera>, Without<Player>)>,
 ) {
     // TODO: Make a smooth camera follow system
-    let player_transform = players.single();
+    let Ok(player_transform) = players.get_single() else {
+        return;
+    };
 
     let pos = player_transform.translation;
 
@@ -0,0 +1,236 @@
+//! Records the `PlayerInput` of every fixed step along with the seed and train, and
+//! plays it back through the same systems. Start with `--record <file>` or
+//! `--replay <file>`, add `--headless` to play a replay without a window, e.g. in CI.
+//!
+//! The file is plain text: a header with the format, game version, seed, fixed step
+//! rate and train composition path, then one line per tick with the bits of the held actions and
+//! the stick tilt. When the recording stops, an `end x y` line per player stores
+//! where they ended up, and playback fails if it doesn't end up at the same place.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Write},
+};
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::{
+    input::PlayerInput,
+    simulation::{arg_value, Simulation, SimulationTick},
+    Interpolated, Player, TrainPath,
+};
+
+const REPLAY_FORMAT: &str = "treen-replay 6";
+const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+struct Playback {
+    seed: u64,
+    hz: f64,
+    train: String,
+    inputs: Vec<PlayerInput>,
+    /// player positions at the end of the recording, sorted by entity
+    end: Vec<Vec2>,
+}
+
+#[derive(Resource, Default)]
+pub struct Replay {
+    playback: Option<Playback>,
+    recorder: Option<LineWriter<File>>,
+    diverged: bool,
+}
+
+impl Replay {
+    /// Loads the replay given with `--replay <file>`, if any.
+    pub fn from_args() -> Self {
+        let playback = arg_value("--replay").and_then(|path| match load(&path) {
+            Ok(playback) => Some(playback),
+            Err(error) => {
+                eprintln!("couldn't load replay {}: {}", path, error);
+                None
+            }
+        });
+
+        Self {
+            playback,
+            ..default()
+        }
+    }
+
+    /// Whether the playback ended somewhere else than the recording.
+    pub fn diverged(&self) -> bool {
+        self.diverged
+    }
+
+    /// Seed the replay was recorded with.
+    pub fn seed(&self) -> Option<u64> {
+        self.playback.as_ref().map(|playback| playback.seed)
+    }
+
+    /// Fixed step rate the replay was recorded with.
+    pub fn hz(&self) -> Option<f64> {
+        self.playback.as_ref().map(|playback| playback.hz)
+    }
+
+    /// Train composition the replay was recorded with.
+    pub fn train(&self) -> Option<&str> {
+        self.playback
+            .as_ref()
+            .map(|playback| playback.train.as_str())
+    }
+
+    /// Starts recording to the file given with `--record <file>`, if any.
+    pub fn record_from_args(&mut self, simulation: &Simulation, hz: f64, train_path: &TrainPath) {
+        let Some(path) = arg_value("--record") else {
+            return;
+        };
+
+        let result = File::create(&path).and_then(|file| {
+            let mut writer = LineWriter::new(file);
+            writeln!(writer, "{}", REPLAY_FORMAT)?;
+            writeln!(writer, "version {}", GAME_VERSION)?;
+            writeln!(writer, "seed {}", simulation.seed)?;
+            writeln!(writer, "hz {}", hz)?;
+            writeln!(writer, "train {}", **train_path)?;
+            Ok(writer)
+        });
+
+        match result {
+            Ok(writer) => self.recorder = Some(writer),
+            Err(error) => eprintln!("couldn't record replay to {}: {}", path, error),
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Playback, String> {
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    let mut lines = BufReader::new(file).lines().map_while(Result::ok);
+
+    if lines.next().as_deref() != Some(REPLAY_FORMAT) {
+        return Err("not a replay file".to_string());
+    }
+
+    let version = lines.next().unwrap_or_default();
+    let version = version.trim_start_matches("version ");
+    if version != GAME_VERSION {
+        println!(
+            "replay recorded with version {}, running {}",
+            version, GAME_VERSION
+        );
+    }
+
+    let seed = lines
+        .next()
+        .and_then(|line| line.strip_prefix("seed ")?.parse().ok())
+        .ok_or("missing seed")?;
+
+    let hz = lines
+        .next()
+        .and_then(|line| line.strip_prefix("hz ")?.parse().ok())
+        .filter(|hz: &f64| *hz > 0.)
+        .ok_or("missing hz")?;
+
+    let train = lines
+        .next()
+        .and_then(|line| Some(line.strip_prefix("train ")?.to_string()))
+        .ok_or("missing train")?;
+
+    let mut inputs = Vec::new();
+    let mut end = Vec::new();
+    for line in lines {
+        if let Some(position) = line.strip_prefix("end ") {
+            let position = position
+                .split_once(' ')
+                .and_then(|(x, y)| Some(Vec2::new(x.parse().ok()?, y.parse().ok()?)))
+                .ok_or(format!("invalid end: {}", line))?;
+            end.push(position);
+        } else {
+            inputs.push(PlayerInput::from_record(&line).ok_or(format!("invalid input: {}", line))?);
+        }
+    }
+
+    Ok(Playback {
+        seed,
+        hz,
+        train,
+        inputs,
+        end,
+    })
+}
+
+/// Overrides the sampled input with the recorded one, and stops the game once the
+/// replay is over, checking that the players ended up where they did when recording.
+pub fn play_replay_input(
+    mut replay: ResMut<Replay>,
+    tick: Res<SimulationTick>,
+    mut input: ResMut<PlayerInput>,
+    players: Query<(Entity, &Transform), With<Player>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(playback) = &replay.playback else {
+        return;
+    };
+
+    match playback.inputs.get(**tick as usize) {
+        Some(recorded) => *input = *recorded,
+        // more ticks can run in the frame the exit is requested
+        None if **tick as usize == playback.inputs.len() => {
+            let end = player_positions(&players);
+            println!("replay finished at tick {}, players at {:?}", **tick, end);
+            // replays recorded before stopping properly don't have an end
+            if !playback.end.is_empty() && end != playback.end {
+                eprintln!("replay diverged, recorded players at {:?}", playback.end);
+                replay.diverged = true;
+            }
+            exit.send(AppExit);
+        }
+        None => {}
+    }
+}
+
+/// Stores where the players ended up once the game exits, runs in `Last`.
+pub fn finish_recording(
+    mut exit_events: EventReader<AppExit>,
+    mut replay: ResMut<Replay>,
+    players: Query<(Entity, &Transform, &Interpolated), With<Player>>,
+) {
+    if exit_events.read().count() == 0 {
+        return;
+    }
+    let Some(mut recorder) = replay.recorder.take() else {
+        return;
+    };
+
+    // the simulated translation, not the one interpolated for rendering
+    let mut players: Vec<(Entity, Vec2)> = players
+        .iter()
+        .map(|(entity, transform, interpolated)| {
+            let translation = interpolated.simulated().unwrap_or(transform.translation);
+            (entity, translation.truncate())
+        })
+        .collect();
+    players.sort_unstable_by_key(|(entity, _)| *entity);
+    for (_, position) in players {
+        if let Err(error) = writeln!(recorder, "end {} {}", position.x, position.y) {
+            eprintln!("couldn't record the end of the replay: {}", error);
+        }
+    }
+}
+
+fn player_positions(players: &Query<(Entity, &Transform), With<Player>>) -> Vec<Vec2> {
+    let mut players: Vec<(Entity, Vec2)> = players
+        .iter()
+        .map(|(entity, transform)| (entity, transform.translation.truncate()))
+        .collect();
+    players.sort_unstable_by_key(|(entity, _)| *entity);
+    players.into_iter().map(|(_, position)| position).collect()
+}
+
+pub fn record_input(mut replay: ResMut<Replay>, input: Res<PlayerInput>) {
+    let Some(recorder) = &mut replay.recorder else {
+        return;
+    };
+
+    if let Err(error) = writeln!(recorder, "{}", input.to_record()) {
+        eprintln!("couldn't record input: {}", error);
+    }
+}
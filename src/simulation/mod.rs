@@ -0,0 +1,86 @@
+//! Everything needed to make the `FixedUpdate` chain reproducible: given the same
+//! seed and the same input on every tick, the simulation plays out the same way.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct Simulation {
+    pub seed: u64,
+}
+
+impl Simulation {
+    /// Uses `--seed <n>` when given, otherwise a seed from the clock that gets printed
+    /// so the run can be reproduced.
+    pub fn from_args() -> Self {
+        let seed = match arg_value("--seed").and_then(|seed| seed.parse().ok()) {
+            Some(seed) => seed,
+            None => {
+                let seed = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |duration| duration.as_nanos() as u64);
+                println!("simulation seed: {}", seed);
+                seed
+            }
+        };
+        Self { seed }
+    }
+}
+
+/// Value following `name` in the command line arguments.
+pub fn arg_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// Whether the `name` flag is in the command line arguments.
+pub fn has_arg(name: &str) -> bool {
+    std::env::args().any(|arg| arg == name)
+}
+
+/// Number of fixed steps simulated since entering the game.
+#[derive(Resource, Debug, Default, Clone, Copy, Deref, DerefMut)]
+pub struct SimulationTick(pub u64);
+
+/// Seeded random numbers for gameplay, only use it from the fixed step.
+/// SplitMix64, small and good enough for games.
+#[derive(Resource, Debug, Clone)]
+pub struct SimulationRng {
+    state: u64,
+}
+
+impl SimulationRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+}
+
+pub fn reset_simulation(
+    simulation: Res<Simulation>,
+    mut rng: ResMut<SimulationRng>,
+    mut tick: ResMut<SimulationTick>,
+) {
+    *rng = SimulationRng::new(simulation.seed);
+    *tick = SimulationTick::default();
+}
+
+pub fn advance_simulation_tick(mut tick: ResMut<SimulationTick>) {
+    **tick += 1;
+}
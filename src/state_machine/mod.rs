@@ -0,0 +1,502 @@
+//! A small xstate-like state machine shared by the player, wagons and NPCs.
+//!
+//! Transitions are declared once in a [`StateTable`] and every entity owns a
+//! [`StateMachine`] component pointing to it. Systems `send` events to the
+//! machine, and [`update_state_machines`] ticks the timers and publishes
+//! [`StateEntered`] / [`StateExited`] events so `on_enter` / `on_exit` hooks can
+//! be written as regular Bevy systems (see [`entered`] and [`exited`]).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::Hash,
+    sync::Arc,
+    time::Duration,
+};
+
+use bevy::prelude::*;
+
+pub trait MachineState: Debug + Copy + Eq + Hash + Send + Sync + 'static {}
+impl<T: Debug + Copy + Eq + Hash + Send + Sync + 'static> MachineState for T {}
+
+pub trait MachineEvent: Debug + Copy + Eq + Hash + Send + Sync + 'static {}
+impl<T: Debug + Copy + Eq + Hash + Send + Sync + 'static> MachineEvent for T {}
+
+/// Condition evaluated against the machine before taking a transition.
+pub type Guard<S, E> = fn(&StateMachine<S, E>) -> bool;
+
+struct Transition<S, E> {
+    target: S,
+    guard: Option<Guard<S, E>>,
+}
+
+impl<S: MachineState, E: MachineEvent> Transition<S, E> {
+    fn allowed(&self, machine: &StateMachine<S, E>) -> bool {
+        self.guard.map_or(true, |guard| guard(machine))
+    }
+}
+
+/// Declarative transition table, built once and shared between machines.
+pub struct StateTable<S, E> {
+    transitions: HashMap<(S, E), Vec<Transition<S, E>>>,
+    // transcendent transitions can be taken from any state (e.g. `Die`)
+    transcendent: HashMap<E, Transition<S, E>>,
+    // time to wait after leaving a state before it can be entered again
+    cold_starts: HashMap<S, f32>,
+    // time to wait after entering a state before it can be left
+    timeouts: HashMap<S, f32>,
+    // event sent automatically after spending some time in a state
+    triggers: HashMap<S, (f32, E)>,
+}
+
+impl<S: MachineState, E: MachineEvent> Default for StateTable<S, E> {
+    fn default() -> Self {
+        Self {
+            transitions: HashMap::new(),
+            transcendent: HashMap::new(),
+            cold_starts: HashMap::new(),
+            timeouts: HashMap::new(),
+            triggers: HashMap::new(),
+        }
+    }
+}
+
+impl<S: MachineState, E: MachineEvent> StateTable<S, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on(self, from: S, event: E, to: S) -> Self {
+        self.add(from, event, to, None)
+    }
+
+    /// Same as `on`, but the transition is only taken when `guard` passes.
+    /// Guarded transitions are evaluated in declaration order.
+    pub fn on_if(self, from: S, event: E, to: S, guard: Guard<S, E>) -> Self {
+        self.add(from, event, to, Some(guard))
+    }
+
+    /// Transition that can be taken from any state, ignoring timeouts and cold starts.
+    pub fn always(mut self, event: E, to: S) -> Self {
+        self.transcendent.insert(
+            event,
+            Transition {
+                target: to,
+                guard: None,
+            },
+        );
+        self
+    }
+
+    /// `state` can't be entered again until `seconds` after leaving it.
+    // no machine needs it yet, see the tests
+    #[allow(dead_code)]
+    pub fn cold_start(mut self, state: S, seconds: f32) -> Self {
+        self.cold_starts.insert(state, seconds);
+        self
+    }
+
+    /// `state` can't be left until `seconds` after entering it, except through
+    /// transcendent transitions.
+    // no machine needs it yet, see the tests
+    #[allow(dead_code)]
+    pub fn timeout(mut self, state: S, seconds: f32) -> Self {
+        self.timeouts.insert(state, seconds);
+        self
+    }
+
+    /// Sends `event` once the machine has been in `state` for `seconds`.
+    /// The timer is cancelled when the state is left.
+    pub fn after(mut self, state: S, seconds: f32, event: E) -> Self {
+        self.triggers.insert(state, (seconds, event));
+        self
+    }
+
+    fn add(mut self, from: S, event: E, to: S, guard: Option<Guard<S, E>>) -> Self {
+        self.transitions
+            .entry((from, event))
+            .or_default()
+            .push(Transition { target: to, guard });
+        self
+    }
+}
+
+#[derive(Component)]
+pub struct StateMachine<S, E> {
+    state: S,
+    table: Arc<StateTable<S, E>>,
+    clock: f32,
+    entered_at: f32,
+    exited_at: HashMap<S, f32>,
+    trigger: Option<Timer>,
+    flags: HashSet<&'static str>,
+    // (from, to) pairs not yet published as events
+    changes: Vec<(S, S)>,
+}
+
+impl<S: MachineState, E: MachineEvent> StateMachine<S, E> {
+    pub fn new(initial: S, table: Arc<StateTable<S, E>>) -> Self {
+        let mut machine = Self {
+            state: initial,
+            table,
+            clock: 0.,
+            entered_at: 0.,
+            exited_at: HashMap::new(),
+            trigger: None,
+            flags: HashSet::new(),
+            changes: Vec::new(),
+        };
+        machine.reset_trigger();
+        machine
+    }
+
+    pub fn state(&self) -> S {
+        self.state
+    }
+
+    pub fn is(&self, state: S) -> bool {
+        self.state == state
+    }
+
+    pub fn time_in_state(&self) -> f32 {
+        self.clock - self.entered_at
+    }
+
+    /// Flags are the machine context: facts set by gameplay systems and read by guards.
+    pub fn flag(&self, name: &'static str) -> bool {
+        self.flags.contains(name)
+    }
+
+    pub fn set_flag(&mut self, name: &'static str, value: bool) {
+        if value {
+            self.flags.insert(name);
+        } else {
+            self.flags.remove(name);
+        }
+    }
+
+    pub fn can(&self, event: E) -> bool {
+        self.resolve(event).is_some()
+    }
+
+    /// Applies `event`, returning whether it was accepted.
+    /// Transitions to the current state are accepted without re-entering it.
+    pub fn send(&mut self, event: E) -> bool {
+        let Some(target) = self.resolve(event) else {
+            return false;
+        };
+
+        if target != self.state {
+            self.exited_at.insert(self.state, self.clock);
+            self.changes.push((self.state, target));
+            self.state = target;
+            self.entered_at = self.clock;
+            self.reset_trigger();
+        }
+
+        true
+    }
+
+    pub fn cancel_trigger(&mut self) {
+        self.trigger = None;
+    }
+
+    /// Advances the machine clock, returning the triggered event if its timer expired.
+    pub fn tick(&mut self, delta: Duration) -> Option<E> {
+        self.clock += delta.as_secs_f32();
+
+        let timer = self.trigger.as_mut()?;
+        if timer.tick(delta).just_finished() {
+            self.trigger = None;
+            return self
+                .table
+                .triggers
+                .get(&self.state)
+                .map(|(_, event)| *event);
+        }
+        None
+    }
+
+    fn resolve(&self, event: E) -> Option<S> {
+        if let Some(transition) = self.table.transcendent.get(&event) {
+            return transition.allowed(self).then_some(transition.target);
+        }
+
+        let transition = self
+            .table
+            .transitions
+            .get(&(self.state, event))?
+            .iter()
+            .find(|transition| transition.allowed(self))?;
+
+        if transition.target != self.state {
+            if let Some(timeout) = self.table.timeouts.get(&self.state) {
+                if self.time_in_state() < *timeout {
+                    return None;
+                }
+            }
+
+            if let (Some(cold_start), Some(exited_at)) = (
+                self.table.cold_starts.get(&transition.target),
+                self.exited_at.get(&transition.target),
+            ) {
+                if self.clock - exited_at < *cold_start {
+                    return None;
+                }
+            }
+        }
+
+        Some(transition.target)
+    }
+
+    fn reset_trigger(&mut self) {
+        self.trigger = self
+            .table
+            .triggers
+            .get(&self.state)
+            .map(|(seconds, _)| Timer::from_seconds(*seconds, TimerMode::Once));
+    }
+}
+
+#[derive(Event)]
+pub struct StateEntered<S: MachineState> {
+    pub entity: Entity,
+    pub state: S,
+    pub from: S,
+}
+
+#[derive(Event)]
+pub struct StateExited<S: MachineState> {
+    pub entity: Entity,
+    pub state: S,
+}
+
+pub fn update_state_machines<S: MachineState, E: MachineEvent>(
+    mut query: Query<(Entity, &mut StateMachine<S, E>)>,
+    mut entered_events: EventWriter<StateEntered<S>>,
+    mut exited_events: EventWriter<StateExited<S>>,
+    time: Res<Time>,
+) {
+    for (entity, mut machine) in &mut query {
+        // ticking the clock alone shouldn't flag the machine as changed
+        if let Some(event) = machine.bypass_change_detection().tick(time.delta()) {
+            machine.send(event);
+        }
+
+        if machine.changes.is_empty() {
+            continue;
+        }
+
+        for (from, to) in machine.bypass_change_detection().changes.drain(..) {
+            exited_events.send(StateExited {
+                entity,
+                state: from,
+            });
+            entered_events.send(StateEntered {
+                entity,
+                state: to,
+                from,
+            });
+        }
+    }
+}
+
+/// Run condition for `on_enter` hooks.
+pub fn entered<S: MachineState>(state: S) -> impl FnMut(EventReader<StateEntered<S>>) -> bool {
+    move |mut events: EventReader<StateEntered<S>>| {
+        // read everything so the same event isn't seen again on the next run
+        events.read().filter(|e| e.state == state).count() > 0
+    }
+}
+
+/// Run condition for `on_exit` hooks.
+pub fn exited<S: MachineState>(state: S) -> impl FnMut(EventReader<StateExited<S>>) -> bool {
+    move |mut events: EventReader<StateExited<S>>| {
+        // read everything so the same event isn't seen again on the next run
+        events.read().filter(|e| e.state == state).count() > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Door {
+        Closed,
+        Open,
+        Locked,
+        Broken,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Action {
+        Open,
+        Close,
+        Lock,
+        Kick,
+        Wait,
+    }
+
+    type DoorMachine = StateMachine<Door, Action>;
+
+    fn has_key(machine: &DoorMachine) -> bool {
+        machine.flag("key")
+    }
+
+    fn machine(table: StateTable<Door, Action>) -> DoorMachine {
+        StateMachine::new(Door::Closed, Arc::new(table))
+    }
+
+    fn seconds(seconds: f32) -> Duration {
+        Duration::from_secs_f32(seconds)
+    }
+
+    #[test]
+    fn transitions_follow_the_table() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Open, Door::Open)
+                .on(Door::Open, Action::Close, Door::Closed),
+        );
+
+        assert!(!door.send(Action::Close));
+        assert!(door.is(Door::Closed));
+        assert!(door.send(Action::Open));
+        assert!(door.is(Door::Open));
+        assert!(door.send(Action::Close));
+        assert_eq!(
+            door.changes,
+            vec![(Door::Closed, Door::Open), (Door::Open, Door::Closed)]
+        );
+    }
+
+    #[test]
+    fn guards_are_evaluated_in_order() {
+        let mut door = machine(
+            StateTable::new()
+                .on_if(Door::Closed, Action::Lock, Door::Locked, has_key)
+                .on(Door::Closed, Action::Lock, Door::Broken),
+        );
+
+        door.set_flag("key", true);
+        assert!(door.can(Action::Lock));
+        door.set_flag("key", false);
+        assert!(door.send(Action::Lock));
+        assert!(door.is(Door::Broken));
+    }
+
+    #[test]
+    fn failing_guard_rejects_the_event() {
+        let mut door =
+            machine(StateTable::new().on_if(Door::Closed, Action::Lock, Door::Locked, has_key));
+
+        assert!(!door.send(Action::Lock));
+        door.set_flag("key", true);
+        assert!(door.send(Action::Lock));
+        assert!(door.is(Door::Locked));
+    }
+
+    #[test]
+    fn transcendent_transitions_ignore_the_state_and_timeout() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Lock, Door::Locked)
+                .timeout(Door::Locked, 10.)
+                .always(Action::Kick, Door::Broken),
+        );
+
+        assert!(door.send(Action::Lock));
+        assert!(door.send(Action::Kick));
+        assert!(door.is(Door::Broken));
+        // and from the target state itself, without re-entering it
+        assert!(door.send(Action::Kick));
+        assert_eq!(door.changes.len(), 2);
+    }
+
+    #[test]
+    fn cold_start_delays_entering_again() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Open, Door::Open)
+                .on(Door::Open, Action::Close, Door::Closed)
+                .cold_start(Door::Open, 1.),
+        );
+
+        // never left, so it can be entered right away
+        assert!(door.send(Action::Open));
+        assert!(door.send(Action::Close));
+        assert!(!door.send(Action::Open));
+
+        door.tick(seconds(0.5));
+        assert!(!door.can(Action::Open));
+        door.tick(seconds(0.5));
+        assert!(door.send(Action::Open));
+    }
+
+    #[test]
+    fn timeout_delays_leaving() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Open, Door::Open)
+                .on(Door::Open, Action::Close, Door::Closed)
+                .on(Door::Open, Action::Open, Door::Open)
+                .timeout(Door::Open, 1.),
+        );
+
+        assert!(door.send(Action::Open));
+        assert!(!door.send(Action::Close));
+        // staying in the state isn't leaving it
+        assert!(door.send(Action::Open));
+
+        door.tick(seconds(0.5));
+        assert!(!door.can(Action::Close));
+        door.tick(seconds(0.5));
+        assert_eq!(door.time_in_state(), 1.);
+        assert!(door.send(Action::Close));
+    }
+
+    #[test]
+    fn trigger_fires_once_after_the_delay() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Wait, Door::Open)
+                .after(Door::Closed, 1., Action::Wait),
+        );
+
+        assert_eq!(door.tick(seconds(0.5)), None);
+        assert_eq!(door.tick(seconds(0.5)), Some(Action::Wait));
+        assert_eq!(door.tick(seconds(1.)), None);
+    }
+
+    #[test]
+    fn trigger_is_cancelled() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Open, Door::Open)
+                .on(Door::Open, Action::Close, Door::Closed)
+                .after(Door::Closed, 1., Action::Open),
+        );
+
+        door.cancel_trigger();
+        assert_eq!(door.tick(seconds(2.)), None);
+
+        // entering the state again restarts it
+        assert!(door.send(Action::Open));
+        assert!(door.send(Action::Close));
+        assert_eq!(door.tick(seconds(1.)), Some(Action::Open));
+    }
+
+    #[test]
+    fn leaving_the_state_cancels_its_trigger() {
+        let mut door = machine(
+            StateTable::new()
+                .on(Door::Closed, Action::Open, Door::Open)
+                .after(Door::Closed, 1., Action::Lock),
+        );
+
+        door.tick(seconds(0.5));
+        assert!(door.send(Action::Open));
+        assert_eq!(door.tick(seconds(1.)), None);
+    }
+}